@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)] // keeps the public builtin::fs_impl::fs_impl path
 pub mod fs_impl;
 mod process_impl;
 pub fn match_expression(){
@@ -0,0 +1,598 @@
+//! Integer arithmetic for `$(( ... ))`, following the POSIX/C operator table with bash's
+//! additions (`**`, `base#digits`). Values are 64-bit signed and wrap on overflow.
+
+use std::collections::HashMap;
+
+use crate::error::ArithmeticError;
+
+/// How deeply expressions may nest, counting parentheses, unary operators, the right-hand
+/// sides of `**`, assignments and `?:`, and variables whose values are expressions
+/// themselves, as in `a=b b=c c=1`. Parsing and evaluation recurse
+/// once per level, so this keeps them well inside the stack.
+const MAX_RECURSION_DEPTH:usize = 256;
+
+/// Variable access for the evaluator. Names are used without `$`, as in `x += 1`.
+pub trait ArithmeticContext{
+    fn get_variable(&self,name:&str)->Option<String>;
+    fn set_variable(&mut self,name:&str,value:i64)->Result<(),ArithmeticError>;
+}
+
+impl ArithmeticContext for HashMap<String,String>{
+    fn get_variable(&self,name:&str)->Option<String>{
+        self.get(name).cloned()
+    }
+
+    fn set_variable(&mut self,name:&str,value:i64)->Result<(),ArithmeticError>{
+        self.insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+}
+
+/// Evaluates `expression`, reading and assigning variables through `context`.
+pub fn evaluate(expression:&str,context:&mut impl ArithmeticContext)->Result<i64,ArithmeticError>{
+    Evaluator { context, depth: 0 }.evaluate_text(expression)
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum ArithToken{
+    Number(i64),
+    Name(String),
+    Operator(&'static str)
+}
+
+/// Operators sorted so longer ones are tried first.
+const OPERATORS:[&str;39] = [
+    "<<=", ">>=",
+    "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||",
+    "*=", "/=", "%=", "+=", "-=", "&=", "^=", "|=",
+    "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~", "=", "?", ":", ",", "(", ")"
+];
+
+fn tokenize(expression:&str)->Result<Vec<ArithToken>,ArithmeticError>{
+    let mut tokens = vec![];
+    let mut rest = expression;
+    loop {
+        rest = rest.trim_start();
+        let Some(first) = rest.chars().next() else {
+            return Ok(tokens);
+        };
+        if first.is_ascii_digit(){
+            let len = literal_len(rest);
+            tokens.push(ArithToken::Number(parse_number(&rest[..len])?));
+            rest = &rest[len..];
+        }
+        else if first.is_ascii_alphabetic() || first == '_'{
+            let len = rest.find(|char:char| !(char.is_ascii_alphanumeric() || char == '_')).unwrap_or(rest.len());
+            tokens.push(ArithToken::Name(rest[..len].to_string()));
+            rest = &rest[len..];
+        }
+        else{
+            let Some(operator) = OPERATORS.into_iter().find(|operator| rest.starts_with(operator)) else {
+                return Err(ArithmeticError::Syntax { message: format!("invalid arithmetic operator (error token is \"{rest}\")") });
+            };
+            tokens.push(ArithToken::Operator(operator));
+            rest = &rest[operator.len()..];
+        }
+    }
+}
+
+/// Length of the number literal `text` starts with, its digits and any base prefix.
+fn literal_len(text:&str)->usize{
+    text.find(|char:char| !(char.is_ascii_alphanumeric() || matches!(char,'#' | '@' | '_'))).unwrap_or(text.len())
+}
+
+/// Parses decimal, `0x` hex, leading-zero octal and `base#digits` literals. Bases go up to
+/// 64, with digits `0-9a-zA-Z@_`; letters are case-insensitive up to base 36.
+fn parse_number(literal:&str)->Result<i64,ArithmeticError>{
+    let invalid = || ArithmeticError::InvalidNumber(literal.to_string());
+    let (base,digits) = if let Some((base,digits)) = literal.split_once('#'){
+        let base:u32 = base.parse().map_err(|_| invalid())?;
+        if !(2..=64).contains(&base){
+            return Err(ArithmeticError::InvalidBase(literal.to_string()));
+        }
+        (base,digits)
+    }
+    else if let Some(hex) = literal.strip_prefix("0x").or_else(|| literal.strip_prefix("0X")){
+        (16,hex)
+    }
+    else if literal.len() > 1 && literal.starts_with('0'){
+        (8,&literal[1..])
+    }
+    else{
+        (10,literal)
+    };
+    if digits.is_empty(){
+        return Err(invalid());
+    }
+    let mut value:i64 = 0;
+    for char in digits.chars(){
+        let digit = match char {
+            '0'..='9' => char as u32 - '0' as u32,
+            'a'..='z' => char as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => char as u32 - 'A' as u32 + 10,
+            'A'..='Z' => char as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(invalid())
+        };
+        if digit >= base{
+            return Err(invalid());
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum ArithExpr{
+    Number(i64),
+    Variable(String),
+    Unary(&'static str,Box<ArithExpr>),
+    /// `++x`/`--x` carry the change to apply, the result is the new value.
+    PreIncrement(String,i64),
+    /// `x++`/`x--`, the result is the old value.
+    PostIncrement(String,i64),
+    /// An operand followed by left-associative operators, applied in order.
+    Binary(Box<ArithExpr>,Vec<(&'static str,ArithExpr)>),
+    Conditional(Box<ArithExpr>,Box<ArithExpr>,Box<ArithExpr>),
+    /// `x = e` has no operator, `x += e` has `+`.
+    Assign(String,Option<&'static str>,Box<ArithExpr>),
+    Comma(Vec<ArithExpr>)
+}
+
+/// Binding strength of binary operators, higher binds tighter.
+fn binary_precedence(operator:&str)->Option<u8>{
+    Some(match operator {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | ">" | "<=" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        "**" => 11,
+        _ => return None
+    })
+}
+
+struct ArithParser{
+    tokens:Vec<ArithToken>,
+    position:usize,
+    /// How deeply the expression being parsed nests so far, starting from the depth it is
+    /// evaluated at.
+    depth:usize
+}
+
+impl ArithParser{
+    fn peek(&self)->Option<&ArithToken>{
+        self.tokens.get(self.position)
+    }
+
+    fn peek_operator(&self)->Option<&'static str>{
+        match self.peek() {
+            Some(ArithToken::Operator(operator)) => Some(operator),
+            _ => None
+        }
+    }
+
+    fn expect(&mut self,operator:&str)->Result<(),ArithmeticError>{
+        if self.peek_operator() == Some(operator){
+            self.position += 1;
+            return Ok(());
+        }
+        Err(self.unexpected())
+    }
+
+    fn unexpected(&self)->ArithmeticError{
+        let message = match self.peek() {
+            Some(ArithToken::Operator(operator)) => format!("operand expected (error token is \"{operator}\")"),
+            Some(ArithToken::Number(number)) => format!("syntax error in expression (error token is \"{number}\")"),
+            Some(ArithToken::Name(name)) => format!("syntax error in expression (error token is \"{name}\")"),
+            None => String::from("operand expected")
+        };
+        ArithmeticError::Syntax { message }
+    }
+
+    /// Goes one level deeper into the expression, failing past [`MAX_RECURSION_DEPTH`].
+    /// Nothing goes back up after an error, since it ends the parse.
+    fn enter(&mut self)->Result<(),ArithmeticError>{
+        if self.depth >= MAX_RECURSION_DEPTH{
+            return Err(ArithmeticError::RecursionLimit);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Runs `parse` for an operand one level deeper.
+    fn nested(&mut self,parse:impl FnOnce(&mut Self)->Result<ArithExpr,ArithmeticError>)->Result<ArithExpr,ArithmeticError>{
+        self.enter()?;
+        let expr = parse(self)?;
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn parse_comma(&mut self)->Result<ArithExpr,ArithmeticError>{
+        let mut exprs = vec![self.parse_assignment()?];
+        while self.peek_operator() == Some(","){
+            self.position += 1;
+            exprs.push(self.parse_assignment()?);
+        }
+        if exprs.len() == 1{
+            return Ok(exprs.remove(0));
+        }
+        Ok(ArithExpr::Comma(exprs))
+    }
+
+    fn parse_assignment(&mut self)->Result<ArithExpr,ArithmeticError>{
+        let assignment = match self.tokens.get(self.position+1) {
+            Some(ArithToken::Operator(operator)) if operator.ends_with('=') && !matches!(*operator,"==" | "!=" | "<=" | ">=") => Some(*operator),
+            _ => None
+        };
+        if let Some(operator) = assignment{
+            let ArithToken::Name(name) = self.tokens[self.position].clone() else {
+                return Err(ArithmeticError::NotAssignable);
+            };
+            self.position += 2;
+            let value = self.nested(Self::parse_assignment)?;
+            let binary = operator.strip_suffix('=').filter(|binary| !binary.is_empty())
+                .and_then(|binary| OPERATORS.into_iter().find(|known| *known == binary));
+            return Ok(ArithExpr::Assign(name, binary, Box::new(value)));
+        }
+        self.parse_conditional()
+    }
+
+    fn parse_conditional(&mut self)->Result<ArithExpr,ArithmeticError>{
+        let condition = self.parse_binary(1)?;
+        if self.peek_operator() != Some("?"){
+            return Ok(condition);
+        }
+        self.position += 1;
+        let then_expr = self.nested(Self::parse_comma)?;
+        self.expect(":")?;
+        let else_expr = self.nested(Self::parse_assignment)?;
+        Ok(ArithExpr::Conditional(Box::new(condition), Box::new(then_expr), Box::new(else_expr)))
+    }
+
+    /// Precedence climbing over the binary operators. Operators met at this level never bind
+    /// tighter than the ones before them, so they apply left to right; only `**`, being
+    /// right associative, nests.
+    fn parse_binary(&mut self,min_precedence:u8)->Result<ArithExpr,ArithmeticError>{
+        let first = self.parse_unary()?;
+        let mut rest = vec![];
+        while let Some(operator) = self.peek_operator(){
+            let Some(precedence) = binary_precedence(operator).filter(|precedence| *precedence >= min_precedence) else {
+                break;
+            };
+            self.position += 1;
+            let right = match operator {
+                "**" => self.nested(|parser| parser.parse_binary(precedence))?,
+                _ => self.parse_binary(precedence + 1)?
+            };
+            rest.push((operator,right));
+        }
+        if rest.is_empty(){
+            return Ok(first);
+        }
+        Ok(ArithExpr::Binary(Box::new(first), rest))
+    }
+
+    fn parse_unary(&mut self)->Result<ArithExpr,ArithmeticError>{
+        match self.peek_operator() {
+            Some(operator @ ("++" | "--")) => {
+                self.position += 1;
+                let Some(ArithToken::Name(name)) = self.peek().cloned() else {
+                    return Err(ArithmeticError::NotAssignable);
+                };
+                self.position += 1;
+                Ok(ArithExpr::PreIncrement(name, if operator == "++" { 1 } else { -1 }))
+            },
+            Some(operator @ ("+" | "-" | "!" | "~")) => {
+                self.position += 1;
+                Ok(ArithExpr::Unary(operator, Box::new(self.nested(Self::parse_unary)?)))
+            },
+            _ => self.parse_postfix()
+        }
+    }
+
+    fn parse_postfix(&mut self)->Result<ArithExpr,ArithmeticError>{
+        let primary = self.parse_primary()?;
+        if let ArithExpr::Variable(name) = &primary
+            && let Some(operator @ ("++" | "--")) = self.peek_operator(){
+            self.position += 1;
+            return Ok(ArithExpr::PostIncrement(name.clone(), if operator == "++" { 1 } else { -1 }));
+        }
+        Ok(primary)
+    }
+
+    fn parse_primary(&mut self)->Result<ArithExpr,ArithmeticError>{
+        match self.peek().cloned() {
+            Some(ArithToken::Number(number)) => {
+                self.position += 1;
+                Ok(ArithExpr::Number(number))
+            },
+            Some(ArithToken::Name(name)) => {
+                self.position += 1;
+                Ok(ArithExpr::Variable(name))
+            },
+            Some(ArithToken::Operator("(")) => {
+                self.position += 1;
+                let expr = self.nested(Self::parse_comma)?;
+                self.expect(")")?;
+                Ok(expr)
+            },
+            _ => Err(self.unexpected())
+        }
+    }
+}
+
+struct Evaluator<'a,C:ArithmeticContext>{
+    context:&'a mut C,
+    /// Levels of expression being evaluated, those in variable values included.
+    depth:usize
+}
+
+impl<C:ArithmeticContext> Evaluator<'_,C>{
+    fn evaluate_text(&mut self,expression:&str)->Result<i64,ArithmeticError>{
+        let tokens = tokenize(expression)?;
+        // an empty expression, like an unset variable, is 0
+        if tokens.is_empty(){
+            return Ok(0);
+        }
+        let mut parser = ArithParser { tokens, position: 0, depth: self.depth };
+        let expr = parser.parse_comma()?;
+        if parser.peek().is_some(){
+            return Err(parser.unexpected());
+        }
+        self.evaluate(&expr)
+    }
+
+    /// A variable's value may itself be an expression, e.g. `a=b+1`. A value that is just
+    /// a literal is read the way literals in expressions are, so `010` is octal.
+    fn variable_value(&mut self,name:&str)->Result<i64,ArithmeticError>{
+        let Some(value) = self.context.get_variable(name) else {
+            return Ok(0);
+        };
+        let literal = value.trim();
+        if literal.starts_with(|char:char| char.is_ascii_digit()) && literal_len(literal) == literal.len(){
+            return parse_number(literal);
+        }
+        if self.depth >= MAX_RECURSION_DEPTH{
+            return Err(ArithmeticError::RecursionLimit);
+        }
+        self.depth += 1;
+        let result = self.evaluate_text(&value);
+        self.depth -= 1;
+        result
+    }
+
+    /// The value of `expr`, one level deeper than the expression it is part of. The parser
+    /// already kept the levels below [`MAX_RECURSION_DEPTH`].
+    fn evaluate(&mut self,expr:&ArithExpr)->Result<i64,ArithmeticError>{
+        self.depth += 1;
+        let value = self.evaluate_node(expr);
+        self.depth -= 1;
+        value
+    }
+
+    fn evaluate_node(&mut self,expr:&ArithExpr)->Result<i64,ArithmeticError>{
+        match expr {
+            ArithExpr::Number(number) => Ok(*number),
+            ArithExpr::Variable(name) => self.variable_value(name),
+            ArithExpr::Unary(operator,operand) => {
+                let value = self.evaluate(operand)?;
+                Ok(match *operator {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value
+                })
+            },
+            ArithExpr::PreIncrement(name,delta) => {
+                let value = self.variable_value(name)?.wrapping_add(*delta);
+                self.context.set_variable(name, value)?;
+                Ok(value)
+            },
+            ArithExpr::PostIncrement(name,delta) => {
+                let value = self.variable_value(name)?;
+                self.context.set_variable(name, value.wrapping_add(*delta))?;
+                Ok(value)
+            },
+            ArithExpr::Binary(first,rest) => {
+                let mut value = self.evaluate(first)?;
+                for (operator,operand) in rest{
+                    value = match *operator {
+                        // these two only evaluate the right side when needed
+                        "&&" => (value != 0 && self.evaluate(operand)? != 0) as i64,
+                        "||" => (value != 0 || self.evaluate(operand)? != 0) as i64,
+                        _ => apply_binary(operator, value, self.evaluate(operand)?)?
+                    };
+                }
+                Ok(value)
+            },
+            ArithExpr::Conditional(condition,then_expr,else_expr) => {
+                if self.evaluate(condition)? != 0{
+                    self.evaluate(then_expr)
+                }
+                else{
+                    self.evaluate(else_expr)
+                }
+            },
+            ArithExpr::Assign(name,operator,value) => {
+                let value = self.evaluate(value)?;
+                let value = match operator {
+                    Some(operator) => apply_binary(operator, self.variable_value(name)?, value)?,
+                    None => value
+                };
+                self.context.set_variable(name, value)?;
+                Ok(value)
+            },
+            ArithExpr::Comma(exprs) => {
+                let mut value = 0;
+                for expr in exprs{
+                    value = self.evaluate(expr)?;
+                }
+                Ok(value)
+            }
+        }
+    }
+}
+
+fn apply_binary(operator:&str,left:i64,right:i64)->Result<i64,ArithmeticError>{
+    Ok(match operator {
+        "|" => left | right,
+        "^" => left ^ right,
+        "&" => left & right,
+        "==" => (left == right) as i64,
+        "!=" => (left != right) as i64,
+        "<" => (left < right) as i64,
+        ">" => (left > right) as i64,
+        "<=" => (left <= right) as i64,
+        ">=" => (left >= right) as i64,
+        // shift counts wrap like they do in C on 64-bit values
+        "<<" => left.wrapping_shl(right as u32),
+        ">>" => left.wrapping_shr(right as u32),
+        "+" => left.wrapping_add(right),
+        "-" => left.wrapping_sub(right),
+        "*" => left.wrapping_mul(right),
+        "/" | "%" if right == 0 => return Err(ArithmeticError::DivisionByZero),
+        "/" => left.wrapping_div(right),
+        "%" => left.wrapping_rem(right),
+        "**" => power(left, right)?,
+        _ => unreachable!("not a binary operator: {operator}")
+    })
+}
+
+fn power(mut base:i64,exponent:i64)->Result<i64,ArithmeticError>{
+    if exponent < 0{
+        return Err(ArithmeticError::NegativeExponent);
+    }
+    let mut exponent = exponent as u64;
+    let mut result:i64 = 1;
+    while exponent > 0{
+        if exponent & 1 == 1{
+            result = result.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exponent >>= 1;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn eval(expression:&str)->Result<i64,ArithmeticError>{
+        evaluate(expression, &mut HashMap::new())
+    }
+
+    #[test]
+    fn test_precedence_table() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(eval("-2 ** 2"), Ok(4));
+        assert_eq!(eval("1 << 4 >> 2"), Ok(4));
+        assert_eq!(eval("1 < 2 == 1"), Ok(1));
+        assert_eq!(eval("6 & 3 | 8 ^ 1"), Ok(11));
+        assert_eq!(eval("!0 + ~0"), Ok(0));
+        assert_eq!(eval("0 || 2 && 3"), Ok(1));
+        assert_eq!(eval("1 ? 2 : 0 ? 3 : 4"), Ok(2));
+        assert_eq!(eval("0 ? 2 : 0 ? 3 : 4"), Ok(4));
+        assert_eq!(eval("7 / 2 + 7 % 2 + -7 / 2"), Ok(1));
+        assert_eq!(eval("1, 2, 3"), Ok(3));
+        assert_eq!(eval(""), Ok(0));
+    }
+
+    #[test]
+    fn test_number_literals() {
+        assert_eq!(eval("0x1F + 010 + 2#101"), Ok(31 + 8 + 5));
+        assert_eq!(eval("16#ff"), Ok(255));
+        assert_eq!(eval("36#Z + 36#z"), Ok(70));
+        assert_eq!(eval("64#@ + 64#_ + 64#A"), Ok(62 + 63 + 36));
+        assert_eq!(eval("08"), Err(ArithmeticError::InvalidNumber(String::from("08"))));
+        assert_eq!(eval("65#1"), Err(ArithmeticError::InvalidBase(String::from("65#1"))));
+    }
+
+    #[test]
+    fn test_wrapping_64_bit() {
+        assert_eq!(eval("9223372036854775807 + 1"), Ok(i64::MIN));
+        assert_eq!(eval("(-9223372036854775807 - 1) / -1"), Ok(i64::MIN));
+        assert_eq!(eval("2 ** 64"), Ok(0));
+    }
+
+    #[test]
+    fn test_variables_and_assignment() {
+        let mut variables:HashMap<String,String> = HashMap::new();
+        variables.insert(String::from("x"), String::from("5"));
+        variables.insert(String::from("expr"), String::from("x * 2"));
+        assert_eq!(evaluate("x += 3", &mut variables), Ok(8));
+        assert_eq!(evaluate("y = x++ + ++x", &mut variables), Ok(18));
+        assert_eq!(variables["x"], "10");
+        assert_eq!(variables["y"], "18");
+        assert_eq!(evaluate("expr + unset", &mut variables), Ok(20));
+        assert_eq!(evaluate("x <<= 1, x |= 1, x", &mut variables), Ok(21));
+        assert_eq!(evaluate("a = b = 4", &mut variables), Ok(4));
+        assert_eq!(variables["a"], "4");
+    }
+
+    #[test]
+    fn test_variable_values_follow_literal_rules() {
+        let mut variables:HashMap<String,String> = HashMap::new();
+        for (name,value) in [("octal","010"),("hex"," 0x1f "),("based","2#101"),("negative","-7"),("bad","08")]{
+            variables.insert(String::from(name), String::from(value));
+        }
+        assert_eq!(evaluate("octal", &mut variables), Ok(8));
+        assert_eq!(evaluate("hex", &mut variables), Ok(31));
+        assert_eq!(evaluate("based", &mut variables), Ok(5));
+        assert_eq!(evaluate("negative", &mut variables), Ok(-7));
+        assert_eq!(evaluate("bad", &mut variables), Err(ArithmeticError::InvalidNumber(String::from("08"))));
+        assert_eq!(evaluate("octal += 1", &mut variables), Ok(9));
+        assert_eq!(variables["octal"], "9");
+    }
+
+    #[test]
+    fn test_short_circuit_skips_side_effects() {
+        let mut variables:HashMap<String,String> = HashMap::new();
+        assert_eq!(evaluate("0 && (x = 1)", &mut variables), Ok(0));
+        assert_eq!(evaluate("1 || x++", &mut variables), Ok(1));
+        assert_eq!(evaluate("1 ? 2 : 1 / 0", &mut variables), Ok(2));
+        assert!(!variables.contains_key("x"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(eval("1 / 0"), Err(ArithmeticError::DivisionByZero));
+        assert_eq!(eval("1 % (2 - 2)"), Err(ArithmeticError::DivisionByZero));
+        assert_eq!(eval("2 ** -1"), Err(ArithmeticError::NegativeExponent));
+        assert_eq!(eval("3 = 4"), Err(ArithmeticError::NotAssignable));
+        assert!(matches!(eval("1 +"), Err(ArithmeticError::Syntax { .. })));
+        assert!(matches!(eval("(1"), Err(ArithmeticError::Syntax { .. })));
+        assert!(matches!(eval("1 2"), Err(ArithmeticError::Syntax { .. })));
+        let mut variables:HashMap<String,String> = HashMap::new();
+        variables.insert(String::from("a"), String::from("a + 1"));
+        assert_eq!(evaluate("a", &mut variables), Err(ArithmeticError::RecursionLimit));
+    }
+
+    #[test]
+    fn test_deep_nesting_is_an_error() {
+        let parenthesized = |depth:usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(eval(&parenthesized(200)), Ok(1));
+        assert_eq!(eval(&parenthesized(3000)), Err(ArithmeticError::RecursionLimit));
+        assert_eq!(eval(&format!("{}1", "~ ".repeat(3000))), Err(ArithmeticError::RecursionLimit));
+        assert_eq!(eval(&format!("1{}", " ** 1".repeat(3000))), Err(ArithmeticError::RecursionLimit));
+        // flat left-associative chains do not nest
+        assert_eq!(eval(&format!("1{}", " + 1".repeat(3000))), Ok(3001));
+        assert_eq!(eval(&format!("2{}", " * 2 / 2 - 1 + 1".repeat(3000))), Ok(2));
+        assert_eq!(eval(&format!("0{}", ", 1".repeat(3000))), Ok(1));
+        assert_eq!(eval(&format!("1{}", " && 1 || 0".repeat(3000))), Ok(1));
+        let mut variables:HashMap<String,String> = HashMap::new();
+        // a variable's expression counts from the depth it is used at
+        variables.insert(String::from("a"), format!("{}1", "~ ".repeat(150)));
+        assert_eq!(evaluate(&format!("{}1", "~ ".repeat(100)), &mut variables), Ok(1));
+        assert_eq!(evaluate(&format!("{}a", "~ ".repeat(150)), &mut variables), Err(ArithmeticError::RecursionLimit));
+    }
+}
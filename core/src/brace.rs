@@ -0,0 +1,216 @@
+//! Brace expansion, the first stage of expanding a command word: `a{b,c}d` becomes `abd`
+//! and `acd`, and `{1..5}` the numbers from 1 to 5. It works on the word's segments
+//! before anything else is expanded, so only braces in unquoted literal text count.
+
+use crate::parser::ASTExpr;
+
+/// A word split into unquoted literal characters, where braces can be found, and the
+/// other segments kept whole.
+#[derive(Debug,Clone)]
+enum Item<'a>{
+    Char(char),
+    Segment(&'a ASTExpr)
+}
+
+/// The words `segments` expands to, just the segments themselves when they hold no brace
+/// expression.
+pub(crate) fn expand_braces(segments:&[ASTExpr])->Vec<Vec<ASTExpr>>{
+    let mut items = vec![];
+    for segment in segments{
+        match segment {
+            ASTExpr::Literal(text) => items.extend(text.chars().map(Item::Char)),
+            segment => items.push(Item::Segment(segment))
+        }
+    }
+    expand_items(&items).into_iter().map(|items| to_segments(&items)).collect()
+}
+
+fn expand_items<'a>(items:&[Item<'a>])->Vec<Vec<Item<'a>>>{
+    let Some((start,end,alternatives)) = find_brace_expression(items) else {
+        return vec![items.to_vec()];
+    };
+    let suffixes = expand_items(&items[end + 1..]);
+    let mut words = vec![];
+    for alternative in alternatives{
+        for suffix in &suffixes{
+            let mut word = items[..start].to_vec();
+            word.extend(alternative.iter().cloned());
+            word.extend(suffix.iter().cloned());
+            words.push(word);
+        }
+    }
+    words
+}
+
+/// Finds the first `{...}` that is a comma list or a sequence. Gives where it starts and
+/// ends along with what it expands to, each alternative already expanded in turn.
+fn find_brace_expression<'a>(items:&[Item<'a>])->Option<(usize,usize,Vec<Vec<Item<'a>>>)>{
+    for start in 0..items.len(){
+        if !matches!(items[start],Item::Char('{')){
+            continue;
+        }
+        let Some(end) = matching_brace(items,start) else {
+            continue;
+        };
+        let inner = &items[start + 1..end];
+        let commas = top_level_commas(inner);
+        if !commas.is_empty(){
+            let mut alternatives = vec![];
+            let mut from = 0;
+            for comma in commas.into_iter().chain([inner.len()]){
+                alternatives.extend(expand_items(&inner[from..comma]));
+                from = comma + 1;
+            }
+            return Some((start,end,alternatives));
+        }
+        let text:Option<String> = inner.iter().map(|item| match item {
+            Item::Char(char) => Some(*char),
+            Item::Segment(_) => None
+        }).collect();
+        if let Some(values) = text.as_deref().and_then(sequence){
+            let alternatives = values.into_iter().map(|value| value.chars().map(Item::Char).collect()).collect();
+            return Some((start,end,alternatives));
+        }
+    }
+    None
+}
+
+/// Index of the `}` closing the `{` at `start`.
+fn matching_brace(items:&[Item],start:usize)->Option<usize>{
+    let mut depth = 0;
+    for (index,item) in items.iter().enumerate().skip(start){
+        match item {
+            Item::Char('{') => depth += 1,
+            Item::Char('}') => {
+                depth -= 1;
+                if depth == 0{
+                    return Some(index);
+                }
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Indexes of the commas in `items` that are not inside nested braces.
+fn top_level_commas(items:&[Item])->Vec<usize>{
+    let mut depth = 0;
+    let mut commas = vec![];
+    for (index,item) in items.iter().enumerate(){
+        match item {
+            Item::Char('{') => depth += 1,
+            Item::Char('}') => depth -= 1,
+            Item::Char(',') if depth == 0 => commas.push(index),
+            _ => {}
+        }
+    }
+    commas
+}
+
+/// The values of `first..last` or `first..last..step`, between integers or single
+/// letters. Integers written with a leading zero are padded to the same width.
+fn sequence(text:&str)->Option<Vec<String>>{
+    let parts:Vec<&str> = text.split("..").collect();
+    let (first,last,step) = match parts.as_slice() {
+        [first,last] => (*first,*last,1),
+        [first,last,step] => (*first,*last,step.parse::<i64>().ok()?.unsigned_abs().max(1)),
+        _ => return None
+    };
+    if let (Ok(start),Ok(end)) = (first.parse::<i64>(),last.parse::<i64>()){
+        let padded = |number:&str| {
+            let digits = number.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = if padded(first) || padded(last) { first.len().max(last.len()) } else { 0 };
+        return Some(range(start,end,step).map(|value| format!("{value:0width$}")).collect());
+    }
+    let mut first = first.chars();
+    let mut last = last.chars();
+    match (first.next(),first.next(),last.next(),last.next()) {
+        (Some(start),None,Some(end),None) if start.is_ascii_alphabetic() && end.is_ascii_alphabetic() => Some(
+            range(start as i64,end as i64,step)
+                .filter_map(|value| char::from_u32(value as u32))
+                .map(String::from)
+                .collect()
+        ),
+        _ => None
+    }
+}
+
+/// From `start` to `end` inclusive, counting down when `end` is smaller.
+fn range(start:i64,end:i64,step:u64)->Box<dyn Iterator<Item = i64>>{
+    match start <= end {
+        true => Box::new((start..=end).step_by(step as usize)),
+        false => Box::new((end..=start).rev().step_by(step as usize))
+    }
+}
+
+/// Puts the characters back together into literal segments.
+fn to_segments(items:&[Item])->Vec<ASTExpr>{
+    let mut segments = vec![];
+    let mut literal = String::from("");
+    for item in items{
+        match item {
+            Item::Char(char) => literal.push(*char),
+            Item::Segment(segment) => {
+                if !literal.is_empty(){
+                    segments.push(ASTExpr::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push((*segment).clone());
+            }
+        }
+    }
+    if !literal.is_empty(){
+        segments.push(ASTExpr::Literal(literal));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::parser::{Command, parse_program};
+
+    /// The words brace expansion makes of the single argument in `source`, with quoted
+    /// parts shown in their quotes.
+    fn expand(source:&str)->Vec<String>{
+        let program = parse_program(&format!("echo {source}")).unwrap();
+        let Command::Simple(command) = &program.items[0].and_or.first.commands[0] else { panic!() };
+        expand_braces(&command.words[1].segments).into_iter().map(|segments| {
+            segments.iter().map(|segment| match segment {
+                ASTExpr::Literal(text) => text.clone(),
+                ASTExpr::SingleQuoted(text) => format!("'{text}'"),
+                segment => format!("{segment:?}")
+            }).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn test_comma_lists_nest() {
+        assert_eq!(expand("src/{bin,lib,tests}"),vec!["src/bin","src/lib","src/tests"]);
+        assert_eq!(expand("a{b,c{d,e}}f"),vec!["abf","acdf","acef"]);
+        assert_eq!(expand("{a,b}{1,2}"),vec!["a1","a2","b1","b2"]);
+        assert_eq!(expand("x{,y}"),vec!["x","xy"]);
+    }
+
+    #[test]
+    fn test_sequences() {
+        assert_eq!(expand("{1..5}"),vec!["1","2","3","4","5"]);
+        assert_eq!(expand("{1..10..3}"),vec!["1","4","7","10"]);
+        assert_eq!(expand("{3..1}"),vec!["3","2","1"]);
+        assert_eq!(expand("{01..03}"),vec!["01","02","03"]);
+        assert_eq!(expand("{-1..1}"),vec!["-1","0","1"]);
+        assert_eq!(expand("{a..e..2}"),vec!["a","c","e"]);
+        assert_eq!(expand("{1..a}"),vec!["{1..a}"]);
+    }
+
+    #[test]
+    fn test_quoted_and_lone_braces_stay() {
+        assert_eq!(expand("{}"),vec!["{}"]);
+        assert_eq!(expand("{a}"),vec!["{a}"]);
+        assert_eq!(expand("'{a,b}'"),vec!["'{a,b}'"]);
+        assert_eq!(expand("{a'{x,y}'}{1,2}"),vec!["{a'{x,y}'}1","{a'{x,y}'}2"]);
+        assert_eq!(expand("{a,'b,c'}"),vec!["a","'b,c'"]);
+    }
+}
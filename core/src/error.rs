@@ -1,6 +1,8 @@
 use nix::errno::Errno;
 use derive_more::{Debug, Display, Error};
 
+use crate::tokenizer::Span;
+
 #[derive(Debug, Display, Error)]
 pub enum FsError {
     #[debug("CwdError(errno={errno:?},")]
@@ -18,6 +20,202 @@ pub enum FsError {
         // #[from]
         errno: Errno,
     },
+    #[debug("ReadDirError(errno={errno:?},")]
+    #[display("Failed to read directory")]
+    ReadDirError{
+        errno: Errno,
+    },
     #[display("Filesystem error: {_0}")]
     Other(#[error(not(source))] String),
 }
+
+#[derive(Debug, Display, Error, PartialEq, Eq)]
+pub enum TokenizeError {
+    #[display("unterminated {} quote", if *quote == '\'' { "single" } else { "double" })]
+    UnterminatedQuote {
+        #[error(not(source))]
+        quote: char,
+        span: Span,
+    },
+    #[display("bad substitution: {text}")]
+    BadSubstitution {
+        text: String,
+        span: Span,
+    },
+    #[display("invalid character {char:?}")]
+    InvalidCharacter {
+        #[error(not(source))]
+        char: char,
+        span: Span,
+    },
+    #[display("unexpected end of input, the command continues on the next line")]
+    IncompleteInput {
+        span: Span,
+    },
+}
+
+impl TokenizeError {
+    pub fn span(&self) -> Span {
+        match self {
+            TokenizeError::UnterminatedQuote { span, .. }
+            | TokenizeError::BadSubstitution { span, .. }
+            | TokenizeError::InvalidCharacter { span, .. }
+            | TokenizeError::IncompleteInput { span } => *span,
+        }
+    }
+
+    /// Whether reading more input could complete the command, as an interactive shell
+    /// does with a continuation prompt.
+    pub fn is_incomplete(&self) -> bool {
+        matches!(self, TokenizeError::UnterminatedQuote { .. } | TokenizeError::IncompleteInput { .. })
+    }
+}
+
+#[derive(Debug, Display, Error, PartialEq, Eq)]
+pub enum ArithmeticError {
+    #[display("division by 0")]
+    DivisionByZero,
+    #[display("exponent less than 0")]
+    NegativeExponent,
+    #[display("value too great for base: {_0}")]
+    InvalidNumber(#[error(not(source))] String),
+    #[display("invalid arithmetic base: {_0}")]
+    InvalidBase(#[error(not(source))] String),
+    #[display("attempted assignment to non-variable")]
+    NotAssignable,
+    #[display("expression recursion level exceeded")]
+    RecursionLimit,
+    #[display("{message}")]
+    Syntax {
+        message: String,
+    },
+    #[display("{name}: {reason}")]
+    Assignment {
+        name: String,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Display, Error, PartialEq, Eq)]
+pub enum VariableError {
+    #[display("{name}: readonly variable")]
+    Readonly {
+        #[error(not(source))]
+        name: String,
+    },
+    #[display("`{name}': not a valid identifier")]
+    InvalidName {
+        #[error(not(source))]
+        name: String,
+    },
+    #[display("{name}: {source}")]
+    Arithmetic {
+        name: String,
+        source: ArithmeticError,
+    },
+}
+
+/// An error that stops a command from running, along with the span of the word,
+/// redirection or command it came from.
+#[derive(Debug, Display, Error, PartialEq, Eq)]
+pub enum ExecutionError {
+    #[display("{target}: {reason}")]
+    Redirection {
+        target: String,
+        reason: String,
+        span: Span,
+    },
+    #[display("{target}: ambiguous redirect")]
+    AmbiguousRedirect {
+        #[error(not(source))]
+        target: String,
+        span: Span,
+    },
+    #[display("{expression}: {source}")]
+    Arithmetic {
+        expression: String,
+        source: ArithmeticError,
+        span: Span,
+    },
+    #[display("{source}")]
+    Variable {
+        source: VariableError,
+        span: Span,
+    },
+    #[display("{name}: {message}")]
+    UnsetParameter {
+        name: String,
+        message: String,
+        span: Span,
+    },
+    #[display("${name}: cannot assign in this way")]
+    CannotAssign {
+        #[error(not(source))]
+        name: String,
+        span: Span,
+    },
+    #[display("no match: {pattern}")]
+    NoMatch {
+        #[error(not(source))]
+        pattern: String,
+        span: Span,
+    },
+    #[display("{name}: maximum function nesting level exceeded ({limit})")]
+    FunctionDepth {
+        name: String,
+        limit: usize,
+        span: Span,
+    },
+    #[display("{keyword}: not supported yet")]
+    Unsupported {
+        #[error(not(source))]
+        keyword: String,
+        span: Span,
+    },
+}
+
+impl ExecutionError {
+    pub fn span(&self) -> Span {
+        match self {
+            ExecutionError::Redirection { span, .. }
+            | ExecutionError::AmbiguousRedirect { span, .. }
+            | ExecutionError::Arithmetic { span, .. }
+            | ExecutionError::Variable { span, .. }
+            | ExecutionError::UnsetParameter { span, .. }
+            | ExecutionError::CannotAssign { span, .. }
+            | ExecutionError::NoMatch { span, .. }
+            | ExecutionError::FunctionDepth { span, .. }
+            | ExecutionError::Unsupported { span, .. } => *span,
+        }
+    }
+}
+
+/// Renders an error the way rustc does: the message, where it happened and the offending
+/// source line with the span underlined.
+///
+/// ```text
+/// error: unterminated single quote
+///  --> deploy.sh:3:6
+///   |
+/// 3 | echo 'abc
+///   |      ^^^^
+/// ```
+pub fn render_diagnostic(source_name: &str, source: &str, span: Span, message: &str) -> String {
+    let line_text = source.lines().nth(span.line.saturating_sub(1)).unwrap_or("");
+    let line_number = span.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+
+    // underline up to the end of the span or of the line, whichever comes first
+    let line_start = source[..span.start.min(source.len())].rfind('\n').map_or(0, |index| index + 1);
+    let line_end = line_start + line_text.len();
+    let underline_end = span.end.min(line_end).max(span.start);
+    let underline_len = source.get(span.start..underline_end).map_or(0, |text| text.chars().count()).max(1);
+
+    format!(
+        "error: {message}\n{gutter}--> {source_name}:{}:{}\n{gutter} |\n{line_number} | {line_text}\n{gutter} | {}{}",
+        span.line,
+        span.column,
+        " ".repeat(span.column.saturating_sub(1)),
+        "^".repeat(underline_len),
+    )
+}
@@ -0,0 +1,1084 @@
+//! Runs the AST built by the parser: command lists, and-or lists, pipelines and simple
+//! commands along with their redirections.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::{Seek, Write};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Instant;
+
+use nix::errno::Errno;
+use nix::sys::wait::{WaitPidFlag, WaitStatus, waitpid};
+use nix::unistd::{Pid, getpid};
+
+use crate::error::{ExecutionError, render_diagnostic};
+use crate::fs::syscalls::get_cwd_impl;
+use crate::parser::{Assignment, AndOrList, AndOrOperator, CaseItem, CaseTerminator, Command, CommandList, CompoundCommand, CompoundKind, FunctionDefinition, ParserError, Pipeline, Redirection, RedirectionKind, RedirectionTarget, ShellWord, SimpleCommand, parse_program};
+use crate::process::process_impl::{RedirectionFileType, duplicate_fd, exec_or_exit, flush_stdout, open_file_for_redirection, perform_piping, restore_fd, save_fd, spawn_new_process, spawn_subshell, wait_for_process};
+use crate::options::ShellOptions;
+use crate::tokenizer::Span;
+use crate::variables::{Variable, Variables};
+use crate::{BUILTINS, SPECIAL_BUILTINS, match_expression};
+
+/// Fds changed by redirections along with copies of what they were before.
+type SavedFds = Vec<(RawFd,Option<RawFd>)>;
+
+/// Where commands are looked for when `PATH` is unset.
+const DEFAULT_PATH:&str = "/usr/local/bin:/usr/bin:/bin";
+
+/// How deeply function calls can nest before a call fails, which stops runaway recursion
+/// well before the shell's own stack runs out.
+const MAX_FUNCTION_DEPTH:usize = 256;
+
+/// A `break` or `continue` on its way out to the loop it applies to, counting the loops
+/// still to leave, or a `return` on its way out of a function.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub(crate) enum ControlFlow{
+    Break(usize),
+    Continue(usize),
+    /// `return` with the status the function gives.
+    Return(i32)
+}
+
+/// Text of a program and the name errors give for it, such as `<stdin>` or a script's path.
+pub(crate) struct Source{
+    name:String,
+    text:String
+}
+
+/// A defined function along with the source its spans point into.
+struct Function{
+    definition:FunctionDefinition,
+    source:Option<Rc<Source>>
+}
+
+/// State of a running shell.
+pub struct Shell{
+    pub variables:Variables,
+    /// Options set with `shopt`.
+    pub options:ShellOptions,
+    /// `$1` onwards.
+    pub positional_parameters:Vec<String>,
+    /// `$0`
+    pub shell_name:String,
+    /// `$$`, which stays the pid of the main shell inside subshells.
+    pub shell_pid:Pid,
+    /// Exit status of the last pipeline, `$?`.
+    pub last_status:i32,
+    /// Pid of the last asynchronous list, `$!`.
+    pub last_background_pid:Option<Pid>,
+    /// Set for the shell reading commands from a user, cleared in forked children.
+    pub interactive:bool,
+    /// Asynchronous lists not waited for yet.
+    background_jobs:Vec<Pid>,
+    /// Status of the last command substitution in the command being expanded, which is
+    /// the status of a command that has no command word.
+    pub(crate) substitution_status:Option<i32>,
+    /// Loops currently running, which `break` and `continue` can leave.
+    pub(crate) loop_depth:usize,
+    /// Set by `break`, `continue` and `return`, stops the lists running until the loop
+    /// or function sees it.
+    pub(crate) control_flow:Option<ControlFlow>,
+    /// Functions defined so far, by name.
+    functions:HashMap<String,Rc<Function>>,
+    /// Function calls currently running.
+    pub(crate) function_depth:usize,
+    /// The program being run, which runtime errors are shown in. None where spans do not
+    /// point into a known text, as inside command substitutions.
+    pub(crate) source:Option<Rc<Source>>
+}
+
+impl Default for Shell{
+    fn default()->Self{
+        Self::new()
+    }
+}
+
+impl Shell{
+    pub fn new()->Self{
+        let mut variables = Variables::from_environment();
+        // an inherited PWD is kept only while it still names the current directory
+        if let Ok(current) = get_cwd_impl()
+            && variables.get("PWD").is_none_or(|pwd| std::fs::canonicalize(pwd).ok().as_ref() != Some(&current)){
+            let _ = variables.set("PWD",&current.display().to_string());
+        }
+        Shell {
+            variables,
+            options: ShellOptions::default(),
+            positional_parameters: vec![],
+            shell_name: String::from("hsh"),
+            shell_pid: getpid(),
+            last_status: 0,
+            last_background_pid: None,
+            interactive: false,
+            background_jobs: vec![],
+            substitution_status: None,
+            loop_depth: 0,
+            control_flow: None,
+            functions: HashMap::new(),
+            function_depth: 0,
+            source: None
+        }
+    }
+
+    /// Parses and runs `source`, giving the status of the last command run. `name` is what
+    /// runtime errors call the source, like `<stdin>` or a script's path.
+    pub fn run_program(&mut self,name:&str,source:&str)->Result<i32,ParserError>{
+        let program = parse_program(source)?;
+        Ok(self.execute_program(name,source,&program))
+    }
+
+    /// Runs `program`, already parsed from `source`, so that runtime errors can show the
+    /// line they happened on.
+    pub fn execute_program(&mut self,name:&str,source:&str,program:&CommandList)->i32{
+        self.source = Some(Rc::new(Source { name: name.to_string(), text: source.to_string() }));
+        self.execute_list(program)
+    }
+
+    pub fn execute_list(&mut self,list:&CommandList)->i32{
+        for item in &list.items{
+            if self.control_flow.is_some(){
+                break;
+            }
+            if item.asynchronous{
+                self.execute_asynchronous(&item.and_or);
+            }
+            else{
+                self.execute_and_or(&item.and_or);
+            }
+        }
+        self.last_status
+    }
+
+    /// Forks the list without waiting for it. Its status is 0, as far as `$?` goes.
+    fn execute_asynchronous(&mut self,and_or:&AndOrList){
+        let spawned = spawn_subshell(|| {
+            self.interactive = false;
+            self.execute_and_or(and_or)
+        });
+        match spawned {
+            Ok(child) => {
+                self.background_jobs.push(child);
+                self.last_background_pid = Some(child);
+                if self.interactive{
+                    eprintln!("[{}] {}",self.background_jobs.len(),child);
+                }
+                self.last_status = 0;
+            },
+            Err(err) => {
+                eprintln!("hsh: fork: {}",err.desc());
+                self.last_status = 1;
+            }
+        }
+    }
+
+    /// Collects asynchronous lists that have finished, so they do not linger as zombies.
+    pub fn reap_background_jobs(&mut self){
+        self.background_jobs.retain(|child| {
+            matches!(waitpid(*child, Some(WaitPidFlag::WNOHANG)),Ok(WaitStatus::StillAlive))
+        });
+    }
+
+    fn execute_and_or(&mut self,and_or:&AndOrList)->i32{
+        let mut status = self.execute_pipeline(&and_or.first);
+        for (operator,pipeline) in &and_or.rest{
+            if self.control_flow.is_some(){
+                break;
+            }
+            let run = match operator {
+                AndOrOperator::And => status == 0,
+                AndOrOperator::Or => status != 0
+            };
+            if run{
+                status = self.execute_pipeline(pipeline);
+            }
+        }
+        status
+    }
+
+    fn execute_pipeline(&mut self,pipeline:&Pipeline)->i32{
+        let started = Instant::now();
+        let status = match pipeline.commands.as_slice() {
+            [command] => self.execute_command(command,false),
+            commands => {
+                let piped = perform_piping(commands.len(), |index| {
+                    self.interactive = false;
+                    self.execute_command(&commands[index],true)
+                });
+                match piped {
+                    // every member is waited for, the last one gives the status
+                    Ok(children) => children.into_iter().fold(0,|_,child| wait_for_process(child)),
+                    Err(err) => {
+                        eprintln!("hsh: pipe: {}",err.desc());
+                        1
+                    }
+                }
+            }
+        };
+        if pipeline.timed{
+            let elapsed = started.elapsed();
+            eprintln!("\nreal\t{}m{:.3}s",elapsed.as_secs() / 60,elapsed.as_secs_f64() % 60.0);
+        }
+        self.last_status = if pipeline.negated { i32::from(status == 0) } else { status };
+        self.last_status
+    }
+
+    /// Runs one command. `in_child` is set when this process was forked just for the
+    /// command, so an external command can replace it instead of forking again.
+    fn execute_command(&mut self,command:&Command,in_child:bool)->i32{
+        match command {
+            Command::Simple(simple) => self.execute_simple(simple,in_child),
+            Command::Compound(compound,redirections) => {
+                let saved = match self.apply_redirections(redirections) {
+                    Ok(saved) => saved,
+                    Err(err) => return self.report(err)
+                };
+                let status = self.execute_compound(compound,in_child);
+                restore_redirections(saved);
+                status
+            },
+            Command::FunctionDefinition(function) => {
+                let function = Function { definition: function.clone(), source: self.source.clone() };
+                self.functions.insert(function.definition.name.clone(),Rc::new(function));
+                0
+            }
+        }
+    }
+
+    fn execute_compound(&mut self,compound:&CompoundCommand,in_child:bool)->i32{
+        match &compound.kind {
+            CompoundKind::BraceGroup(list) => self.execute_list(list),
+            CompoundKind::Subshell(list) if in_child => self.execute_list(list),
+            CompoundKind::Subshell(list) => {
+                match spawn_subshell(|| {
+                    self.interactive = false;
+                    self.execute_list(list)
+                }) {
+                    Ok(child) => wait_for_process(child),
+                    Err(err) => {
+                        eprintln!("hsh: fork: {}",err.desc());
+                        1
+                    }
+                }
+            },
+            CompoundKind::If { branches, else_body } => {
+                for (condition,body) in branches{
+                    let status = self.execute_list(condition);
+                    if self.control_flow.is_some(){
+                        return status;
+                    }
+                    if status == 0{
+                        return self.execute_list(body);
+                    }
+                }
+                // with no branch taken the status is 0, not the last condition's
+                match else_body {
+                    Some(else_body) => self.execute_list(else_body),
+                    None => 0
+                }
+            },
+            CompoundKind::While { condition, body } => self.execute_while(condition,body,false),
+            CompoundKind::Until { condition, body } => self.execute_while(condition,body,true),
+            CompoundKind::For { variable, words, body } => self.execute_for(variable,words.as_deref(),body,compound.span),
+            CompoundKind::Case { subject, items } => self.execute_case(subject,items),
+            kind => {
+                self.report(ExecutionError::Unsupported { keyword: compound_keyword(kind).to_string(), span: compound.span });
+                2
+            }
+        }
+    }
+
+    /// `case` commands: runs the body of the first item with a pattern matching the
+    /// expanded subject, then carries on as its terminator says. The status is the last
+    /// body's, 0 when none ran.
+    fn execute_case(&mut self,subject:&ShellWord,items:&[CaseItem])->i32{
+        let subject = match self.expand_word(subject) {
+            Ok(subject) => subject,
+            Err(err) => return self.report(err)
+        };
+        let mut status = 0;
+        // set after `;&`, which runs the next body whatever its patterns
+        let mut fall_through = false;
+        for item in items{
+            if !fall_through{
+                let mut matched = false;
+                for pattern in &item.patterns{
+                    match self.expand_case_pattern(pattern) {
+                        Ok(pattern) if pattern.matches(&subject) => {
+                            matched = true;
+                            break;
+                        },
+                        Ok(_) => {},
+                        Err(err) => return self.report(err)
+                    }
+                }
+                if !matched{
+                    continue;
+                }
+            }
+            status = match &item.body {
+                Some(body) => self.execute_list(body),
+                None => 0
+            };
+            if self.control_flow.is_some(){
+                break;
+            }
+            match item.terminator {
+                CaseTerminator::Break => break,
+                CaseTerminator::FallThrough => fall_through = true,
+                CaseTerminator::Continue => fall_through = false
+            }
+        }
+        status
+    }
+
+    /// `while` and, with `until` set, `until` loops. The status is the last run of the
+    /// body's, 0 when it never ran.
+    fn execute_while(&mut self,condition:&CommandList,body:&CommandList,until:bool)->i32{
+        self.loop_depth += 1;
+        let mut status = 0;
+        loop {
+            let succeeded = self.execute_list(condition) == 0;
+            if self.leaves_loop() || succeeded == until{
+                break;
+            }
+            status = self.execute_list(body);
+            if self.leaves_loop(){
+                break;
+            }
+        }
+        self.loop_depth -= 1;
+        status
+    }
+
+    /// `for` loops, over the expanded words or without them over the positional
+    /// parameters. `span` is the loop's, for a variable that cannot be assigned.
+    fn execute_for(&mut self,variable:&str,words:Option<&[ShellWord]>,body:&CommandList,span:Span)->i32{
+        let values = match words {
+            Some(words) => {
+                let mut values = vec![];
+                for word in words{
+                    match self.expand_fields(word) {
+                        Ok(fields) => values.extend(fields),
+                        Err(err) => return self.report(err)
+                    }
+                }
+                values
+            },
+            None => self.positional_parameters.clone()
+        };
+        self.loop_depth += 1;
+        let mut status = 0;
+        for value in values{
+            if let Err(source) = self.variables.set(variable,&value){
+                status = self.report(ExecutionError::Variable { source, span });
+                break;
+            }
+            status = self.execute_list(body);
+            if self.leaves_loop(){
+                break;
+            }
+        }
+        self.loop_depth -= 1;
+        status
+    }
+
+    /// Deals with a pending `break` or `continue` for the innermost loop, telling it
+    /// whether to stop. What is left of them after this loop stays pending for the outer
+    /// ones.
+    fn leaves_loop(&mut self)->bool{
+        match self.control_flow.take() {
+            None | Some(ControlFlow::Continue(1)) => false,
+            Some(ControlFlow::Return(status)) => {
+                self.control_flow = Some(ControlFlow::Return(status));
+                true
+            },
+            Some(ControlFlow::Break(1)) => true,
+            Some(ControlFlow::Break(count)) => {
+                self.control_flow = Some(ControlFlow::Break(count - 1));
+                true
+            },
+            Some(ControlFlow::Continue(count)) => {
+                self.control_flow = Some(ControlFlow::Continue(count - 1));
+                true
+            }
+        }
+    }
+
+    fn execute_simple(&mut self,command:&SimpleCommand,in_child:bool)->i32{
+        self.substitution_status = None;
+        let mut words = vec![];
+        for word in &command.words{
+            match self.expand_fields(word) {
+                Ok(fields) => words.extend(fields),
+                Err(err) => return self.report(err)
+            }
+        }
+        let Some(name) = words.first() else {
+            if let Err(err) = self.assign(&command.assignments,false){
+                return self.report(err);
+            }
+            // only redirections, which still create or truncate their files
+            return match self.apply_redirections(&command.redirections) {
+                Ok(saved) => {
+                    restore_redirections(saved);
+                    self.substitution_status.unwrap_or(0)
+                },
+                Err(err) => self.report(err)
+            };
+        };
+        // assignments before special builtins stay, the rest only last for the command
+        let special = BUILTINS.contains(&name.as_str()) && SPECIAL_BUILTINS.contains(&name.as_str());
+        let previous = match self.assign(&command.assignments,!special) {
+            Ok(previous) => previous,
+            Err(err) => return self.report(err)
+        };
+        let status = self.run_command(&words,&command.redirections,command.span,in_child);
+        self.variables.restore(previous);
+        status
+    }
+
+    /// Performs prefix assignments in order. `temporary` ones are also exported and give
+    /// back what they replaced, for [`Variables::restore`] once the command is done.
+    fn assign(&mut self,assignments:&[Assignment],temporary:bool)->Result<Vec<(String,Option<Variable>)>,ExecutionError>{
+        let mut previous = vec![];
+        for assignment in assignments{
+            if temporary{
+                previous.push((assignment.name.clone(),self.variables.variable(&assignment.name).cloned()));
+            }
+            let assigned = self.expand_assignment(&assignment.value)
+                .and_then(|value| self.variables.set(&assignment.name,&value)
+                    .map_err(|source| ExecutionError::Variable { source, span: assignment.span }));
+            if let Err(err) = assigned{
+                self.variables.restore(previous);
+                return Err(err);
+            }
+            if temporary{
+                let _ = self.variables.set_attributes(&assignment.name,|variable| variable.exported = true);
+            }
+        }
+        Ok(previous)
+    }
+
+    /// Runs a function, builtin or external command with its expanded words. Special
+    /// builtins come before functions of the same name, other builtins after them. `span`
+    /// is the command's, for errors.
+    fn run_command(&mut self,words:&[String],redirections:&[Redirection],span:Span,in_child:bool)->i32{
+        let name = &words[0];
+        let special = BUILTINS.contains(&name.as_str()) && SPECIAL_BUILTINS.contains(&name.as_str());
+        if !special && let Some(function) = self.functions.get(name).cloned(){
+            return match self.call_function(&function,words,redirections,span) {
+                Ok(status) => status,
+                Err(err) => self.report(err)
+            };
+        }
+        if BUILTINS.contains(&name.as_str()){
+            let saved = match self.apply_redirections(redirections) {
+                Ok(saved) => saved,
+                Err(err) => return self.report(err)
+            };
+            let status = match_expression(self,words);
+            restore_redirections(saved);
+            return status;
+        }
+        let Some(argv) = words.iter().map(|word| CString::new(word.as_bytes()).ok()).collect::<Option<Vec<CString>>>() else {
+            eprintln!("hsh: {name}: argument contains a NUL byte");
+            return 1;
+        };
+        let Some(path) = self.find_command(name) else {
+            eprintln!("hsh: {name}: command not found");
+            return 127;
+        };
+        let env = self.variables.environment();
+        if in_child{
+            if let Err(err) = self.apply_redirections(redirections){
+                return self.report(err);
+            }
+            exec_or_exit(&path,&argv,&env);
+        }
+        match spawn_new_process(&path,&argv,&env,|| match self.apply_redirections(redirections) {
+            Ok(_) => true,
+            Err(err) => {
+                self.report(err);
+                false
+            }
+        }) {
+            Ok(child) => wait_for_process(child),
+            Err(err) => {
+                eprintln!("hsh: fork: {}",err.desc());
+                1
+            }
+        }
+    }
+
+    /// Runs a function's body with the rest of `words` as the positional parameters and a
+    /// scope of its own for `local` variables. Gives the status `return` set, or else the
+    /// body's. Fails without running the body when calls nest too deeply.
+    fn call_function(&mut self,function:&Function,words:&[String],redirections:&[Redirection],span:Span)->Result<i32,ExecutionError>{
+        if self.function_depth >= MAX_FUNCTION_DEPTH{
+            return Err(ExecutionError::FunctionDepth { name: function.definition.name.clone(), limit: MAX_FUNCTION_DEPTH, span });
+        }
+        let saved = self.apply_redirections(redirections)?;
+        let positional_parameters = std::mem::replace(&mut self.positional_parameters,words[1..].to_vec());
+        // the body's spans point into the source it was defined in
+        let caller_source = std::mem::replace(&mut self.source,function.source.clone());
+        self.function_depth += 1;
+        self.variables.push_scope();
+        let mut status = self.execute_command(&function.definition.body,false);
+        if let Some(ControlFlow::Return(returned)) = self.control_flow{
+            self.control_flow = None;
+            status = returned;
+        }
+        self.variables.pop_scope();
+        self.function_depth -= 1;
+        self.source = caller_source;
+        self.positional_parameters = positional_parameters;
+        restore_redirections(saved);
+        Ok(status)
+    }
+
+    /// Applies redirections in order. On failure the ones already applied are undone.
+    fn apply_redirections(&mut self,redirections:&[Redirection])->Result<SavedFds,ExecutionError>{
+        let mut saved = vec![];
+        for redirection in redirections{
+            if let Err(err) = self.apply_redirection(redirection,&mut saved){
+                restore_redirections(saved);
+                return Err(err);
+            }
+        }
+        Ok(saved)
+    }
+
+    fn apply_redirection(&mut self,redirection:&Redirection,saved:&mut SavedFds)->Result<(),ExecutionError>{
+        let default_fd = match redirection.kind {
+            RedirectionKind::Output | RedirectionKind::Append | RedirectionKind::Clobber
+                | RedirectionKind::DuplicateOutput | RedirectionKind::OutputAndError
+                | RedirectionKind::AppendOutputAndError => 1,
+            RedirectionKind::Input | RedirectionKind::ReadWrite | RedirectionKind::DuplicateInput
+                | RedirectionKind::HereDocument | RedirectionKind::HereString => 0
+        };
+        let fd = redirection.fd.map_or(default_fd,|fd| fd as RawFd);
+        let span = redirection.span;
+        let target = match &redirection.target {
+            RedirectionTarget::HereDocument(body) => {
+                let file = here_document_file(&self.expand_word(body)?,span)?;
+                return redirect_fds(&file,&[fd],saved,span);
+            },
+            RedirectionTarget::Word(word) => self.expand_word(word)?
+        };
+        let open = |file_type| open_file_for_redirection(Path::new(&target),file_type).map_err(|err| {
+            let reason = match err.downcast_ref::<Errno>() {
+                Some(errno) => errno.desc().to_string(),
+                None => err.to_string()
+            };
+            ExecutionError::Redirection { target: target.clone(), reason, span }
+        });
+        match redirection.kind {
+            RedirectionKind::Output | RedirectionKind::Clobber => redirect_fds(&open(RedirectionFileType::WriteOnly)?,&[fd],saved,span),
+            RedirectionKind::Append => redirect_fds(&open(RedirectionFileType::Append)?,&[fd],saved,span),
+            RedirectionKind::Input => redirect_fds(&open(RedirectionFileType::ReadOnly)?,&[fd],saved,span),
+            RedirectionKind::ReadWrite => redirect_fds(&open(RedirectionFileType::ReadWrite)?,&[fd],saved,span),
+            RedirectionKind::OutputAndError => redirect_fds(&open(RedirectionFileType::WriteOnly)?,&[1,2],saved,span),
+            RedirectionKind::AppendOutputAndError => redirect_fds(&open(RedirectionFileType::Append)?,&[1,2],saved,span),
+            RedirectionKind::HereString => redirect_fds(&here_document_file(&format!("{target}\n"),span)?,&[fd],saved,span),
+            RedirectionKind::HereDocument => unreachable!("here-documents have a document as their target"),
+            RedirectionKind::DuplicateOutput | RedirectionKind::DuplicateInput => {
+                if target == "-"{
+                    save(fd,saved);
+                    restore_fd(fd,None);
+                    return Ok(());
+                }
+                if let Ok(source) = target.parse::<RawFd>(){
+                    save(fd,saved);
+                    return duplicate_fd(source,fd).map_err(|errno| ExecutionError::Redirection { target, reason: errno.desc().to_string(), span });
+                }
+                // `>&file` is `&>file`
+                if redirection.kind == RedirectionKind::DuplicateOutput && redirection.fd.is_none(){
+                    return redirect_fds(&open(RedirectionFileType::WriteOnly)?,&[1,2],saved,span);
+                }
+                Err(ExecutionError::AmbiguousRedirect { target, span })
+            }
+        }
+    }
+
+    /// Prints an error that stopped a command, giving status 1. It is shown at the line
+    /// it happened on when the source of the program is known.
+    fn report(&self,err:ExecutionError)->i32{
+        eprintln!("{}",self.diagnostic(&err));
+        1
+    }
+
+    fn diagnostic(&self,err:&ExecutionError)->String{
+        match &self.source {
+            Some(source) => render_diagnostic(&source.name,&source.text,err.span(),&err.to_string()),
+            None => format!("hsh: {err}")
+        }
+    }
+
+    /// Where the external command `name` is, searching `$PATH` when it has no slash.
+    fn find_command(&self,name:&str)->Option<CString>{
+        if name.contains('/'){
+            return CString::new(name).ok();
+        }
+        let search_path = self.variables.get("PATH").unwrap_or(DEFAULT_PATH);
+        search_path.split(':')
+            .map(|directory| Path::new(if directory.is_empty() { "." } else { directory }).join(name))
+            .find(|candidate| is_executable(candidate))
+            .and_then(|candidate| CString::new(candidate.into_os_string().into_vec()).ok())
+    }
+}
+
+fn is_executable(path:&Path)->bool{
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+
+fn save(fd:RawFd,saved:&mut SavedFds){
+    // only the first change to an fd knows what it was originally
+    if !saved.iter().any(|(changed,_)| *changed == fd){
+        saved.push((fd,save_fd(fd)));
+    }
+}
+
+/// Points each of `fds` at `file`, saving what they were first.
+fn redirect_fds(file:&OwnedFd,fds:&[RawFd],saved:&mut SavedFds,span:Span)->Result<(),ExecutionError>{
+    flush_stdout();
+    for fd in fds{
+        save(*fd,saved);
+        duplicate_fd(file.as_raw_fd(),*fd).map_err(|errno| ExecutionError::Redirection {
+            target: fd.to_string(),
+            reason: errno.desc().to_string(),
+            span
+        })?;
+    }
+    Ok(())
+}
+
+/// Puts back fds changed by [`Shell::apply_redirections`], last change first.
+fn restore_redirections(saved:SavedFds){
+    flush_stdout();
+    for (fd,original) in saved.into_iter().rev(){
+        restore_fd(fd,original);
+    }
+}
+
+/// An unnamed temporary file holding `body`, read from the start, for here-documents and
+/// here-strings. A file rather than a pipe, so large bodies cannot block the shell.
+fn here_document_file(body:&str,span:Span)->Result<OwnedFd,ExecutionError>{
+    let to_error = |err:std::io::Error| ExecutionError::Redirection { target: String::from("here-document"), reason: err.to_string(), span };
+    let mut file = tempfile::tempfile().map_err(to_error)?;
+    file.write_all(body.as_bytes()).map_err(to_error)?;
+    file.rewind().map_err(to_error)?;
+    Ok(OwnedFd::from(file))
+}
+
+fn compound_keyword(kind:&CompoundKind)->&'static str{
+    match kind {
+        CompoundKind::BraceGroup(_) => "{",
+        CompoundKind::Subshell(_) => "(",
+        CompoundKind::If { .. } => "if",
+        CompoundKind::While { .. } => "while",
+        CompoundKind::Until { .. } => "until",
+        CompoundKind::For { .. } => "for",
+        CompoundKind::Select { .. } => "select",
+        CompoundKind::Case { .. } => "case",
+        CompoundKind::Conditional(_) => "[["
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::fs::read_to_string;
+    use nix::unistd::{ForkResult, fork};
+    use nix::libc::_exit;
+    use tempfile::tempdir;
+
+    /// Runs `source` in a forked shell, so redirections of fds 0 to 2 cannot disturb other
+    /// tests running at the same time, and gives its status.
+    fn run_in_child(source:&str)->i32{
+        run_with_arguments(source,&[])
+    }
+
+    /// Like [`run_in_child`], with `arguments` as the positional parameters.
+    fn run_with_arguments(source:&str,arguments:&[&str])->i32{
+        flush_stdout();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => wait_for_process(child),
+            ForkResult::Child => {
+                let mut shell = Shell::new();
+                shell.positional_parameters = arguments.iter().map(|argument| argument.to_string()).collect();
+                let status = shell.run_program("<test>",source).unwrap_or(2);
+                flush_stdout();
+                unsafe { _exit(status) }
+            }
+        }
+    }
+
+    /// Runs `source` like [`run_in_child`] and gives its status along with what it wrote to
+    /// stdout.
+    fn output_of(source:&str)->(i32,String){
+        output_with_arguments(source,&[])
+    }
+
+    /// Like [`output_of`], with `arguments` as the positional parameters.
+    fn output_with_arguments(source:&str,arguments:&[&str])->(i32,String){
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let status = run_with_arguments(&format!("{{\n{source}\n}} > '{}'",out.display()),arguments);
+        (status,read_to_string(&out).unwrap())
+    }
+
+    #[test]
+    fn test_pipeline_with_redirection() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let status = run_in_child(&format!("echo hello world | tr a-z A-Z | cat > '{}'",out.display()));
+        assert_eq!(status,0);
+        assert_eq!(read_to_string(&out).unwrap(),"HELLO WORLD\n");
+    }
+
+    #[test]
+    fn test_redirections_append_and_input() {
+        let dir = tempdir().unwrap();
+        let first = dir.path().join("first.txt");
+        let second = dir.path().join("second.txt");
+        let source = format!(
+            "echo one > '{0}'; echo two >> '{0}'; cat < '{0}' > '{1}'; ls '{0}.missing' 2>> '{1}' >&2",
+            first.display(),second.display()
+        );
+        assert_ne!(run_in_child(&source),0);
+        let content = read_to_string(&second).unwrap();
+        assert!(content.starts_with("one\ntwo\n"));
+        assert!(content.contains("missing"));
+    }
+
+    #[test]
+    fn test_and_or_lists_and_statuses() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let source = format!("false && echo no > '{0}'; true || echo no > '{0}'; ! false && echo yes > '{0}'",out.display());
+        assert_eq!(run_in_child(&source),0);
+        assert_eq!(read_to_string(&out).unwrap(),"yes\n");
+        assert_eq!(run_in_child("(exit 3)"),3);
+        assert_eq!(run_in_child("false | true"),0);
+        assert_eq!(run_in_child("true | false"),1);
+        assert_eq!(run_in_child("definitely-not-a-command-hsh"),127);
+    }
+
+    #[test]
+    fn test_here_documents_and_groups() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let source = format!("{{ cat <<EOF; cat <<<there; }} > '{}'\nhi\nEOF\n",out.display());
+        assert_eq!(run_in_child(&source),0);
+        assert_eq!(read_to_string(&out).unwrap(),"hi\nthere\n");
+    }
+
+    #[test]
+    fn test_here_document_bodies_expand_unless_quoted() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let source = format!(
+            "name=world
+cat <<EOF > '{0}'
+hello $name \"${{name}}\" $((1 + 2)) $(echo sub) `echo tick`
+\\$name \\` \\\\ \\\" \\x one \\
+line
+EOF
+cat <<'EOF' >> '{0}'
+hello $name $(echo sub) \\$name
+EOF
+cat <<\\EOF >> '{0}'
+$name
+EOF
+",
+            out.display()
+        );
+        assert_eq!(run_in_child(&source),0);
+        assert_eq!(
+            read_to_string(&out).unwrap(),
+            "hello world \"world\" 3 sub tick\n$name ` \\ \\\" \\x one line\nhello $name $(echo sub) \\$name\n$name\n"
+        );
+    }
+
+    #[test]
+    fn test_exported_variables_reach_commands() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let source = format!(
+            "export SHARED=yes; PRIVATE=no; /bin/sh -c 'echo $SHARED-$PRIVATE' > '{}'",
+            out.display()
+        );
+        assert_eq!(run_in_child(&source),0);
+        assert_eq!(read_to_string(&out).unwrap(),"yes-\n");
+        assert_eq!(run_in_child("PATH=/nonexistent; ls"),127);
+    }
+
+    #[test]
+    fn test_prefix_assignments_last_for_one_command() {
+        let source = "A=1 /bin/sh -c 'echo $A'; echo \"[$A]\"; B=2 echo hi; echo \"[$B]\"; C=3 export D=4; echo $C$D";
+        assert_eq!(output_of(source),(0,String::from("1\n[]\nhi\n[]\n34\n")));
+    }
+
+    #[test]
+    fn test_prefix_assignments_around_special_builtins_and_functions() {
+        let source = "show() { echo \"in [$A] [$x]\"; A=changed; x=changed; }
+A=1 show; echo \"after function [$A]\"
+x=old; x=new show; echo \"restored $x\"
+export E=outer; E=inner /bin/sh -c 'echo \"child $E\"'; /bin/sh -c 'echo \"exported $E\"'
+B=2 :; echo \"special [$B]\"; /bin/sh -c 'echo \"environment [$B]\"'
+C=3 true; echo \"regular [$C]\"";
+        // a special builtin's assignments stay as shell variables, without being exported
+        assert_eq!(
+            output_of(source),
+            (0,String::from("in [1] []\nafter function []\nin [] [new]\nrestored old\nchild inner\nexported outer\nspecial [2]\nenvironment []\nregular []\n"))
+        );
+    }
+
+    #[test]
+    fn test_command_substitution_output_and_status() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let source = format!(
+            "x=$(echo hi; echo there); echo \"$x\" > '{0}'; echo $(printf 'a  b\\n\\n') >> '{0}'; y=$(exit 4); echo $? >> '{0}'; echo `echo back` \"$(false)$?\" >> '{0}'; /bin/sh -c 'echo $#' sh $(echo a b c) \"$(echo a b)\" >> '{0}'",
+            out.display()
+        );
+        assert_eq!(run_in_child(&source),0);
+        assert_eq!(read_to_string(&out).unwrap(),"hi\nthere\na b\n4\nback 1\n4\n");
+    }
+
+    #[test]
+    fn test_cd_keeps_pwd_and_oldpwd() {
+        let dir = tempdir().unwrap();
+        let base = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir(base.join("sub")).unwrap();
+        let out = base.join("out.txt");
+        let source = format!(
+            "cd '{0}'/sub/../sub; echo \"$PWD $OLDPWD\" > '{1}'; cd ..; pwd >> '{1}'; cd - >> '{1}'; echo ~+ ~- >> '{1}'; cd missing || echo failed >> '{1}'",
+            base.display(),out.display()
+        );
+        assert_eq!(run_in_child(&source),0);
+        let previous = std::env::current_dir().unwrap();
+        let base = base.display();
+        assert_eq!(
+            read_to_string(&out).unwrap(),
+            format!("{base}/sub {}\n{base}\n{base}/sub\n{base}/sub {base}\nfailed\n",previous.display())
+        );
+    }
+
+    #[test]
+    fn test_if_elif_else() {
+        let source = "x=2
+if [ $x = 1 ]; then echo one; elif [ $x = 2 ]; then
+    if ! false; then echo two; fi
+else echo other; fi
+if false; then :; fi; echo \"none $?\"
+if true; then (exit 5); fi; echo \"body $?\"
+if false; then :; elif false; then :; else false; fi; echo \"else $?\"
+if (exit 3); then :; else echo \"condition $?\"; fi";
+        assert_eq!(output_of(source),(0,String::from("two\nnone 0\nbody 5\nelse 1\ncondition 3\n")));
+    }
+
+    #[test]
+    fn test_if_status_when_no_branch_runs() {
+        let source = "if false; then :; elif (exit 3); then :; fi; echo \"no branch $?\"
+if (exit 2); then :; elif [ $? = 2 ]; then echo \"elif saw $?\"; fi
+if false; then :; elif false; then :; elif (exit 4); then :; else echo \"else saw $?\"; fi
+(exit 6); if false; then :; fi; echo \"reset $?\"
+if false; then :; elif true; then (exit 7); fi; echo \"elif body $?\"
+if ! true; then :; else echo \"negated $?\"; fi";
+        assert_eq!(
+            output_of(source),
+            (0,String::from("no branch 0\nelif saw 0\nelse saw 4\nreset 0\nelif body 7\nnegated 1\n"))
+        );
+    }
+
+    #[test]
+    fn test_loops_read_redirected_input() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("in.txt");
+        let out = dir.path().join("out.txt");
+        std::fs::write(&input,"first line\n  a b  c  \nlast").unwrap();
+        let source = format!(
+            "while read word rest; do echo \"[$word|$rest]\"; done < '{0}' > '{1}'
+n=0; until [ $n = 3 ]; do n=$((n + 1)); done; echo \"until $n $?\" >> '{1}'
+while false; do :; done; echo \"never $?\" >> '{1}'",
+            input.display(),
+            out.display()
+        );
+        assert_eq!(run_in_child(&source),0);
+        assert_eq!(read_to_string(&out).unwrap(),"[first|line]\n[a|b  c]\nuntil 3 0\nnever 0\n");
+    }
+
+    #[test]
+    fn test_for_break_and_continue() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let source = format!(
+            "for x in a{{1,2}} 'b c'; do echo \"$x\"; done > '{0}'
+for arg; do echo \"arg $arg\"; done >> '{0}'
+for i in 1 2 3; do
+    for j in 1 2 3; do
+        if [ $j = 2 ]; then continue 2; fi
+        if [ $i = 3 ]; then break 2; fi
+        echo \"$i$j\"
+    done
+    echo never
+done >> '{0}'
+for i in 1 2; do while true; do break 5; done; echo \"after $i\"; done >> '{0}'",
+            out.display()
+        );
+        assert_eq!(run_with_arguments(&source,&["one","two words"]),0);
+        assert_eq!(
+            read_to_string(&out).unwrap(),
+            "a1\na2\nb c\narg one\narg two words\n11\n21\n"
+        );
+    }
+
+    #[test]
+    fn test_case_patterns_and_terminators() {
+        let source = "star='*'
+for word in main.rs Makefile '*' x; do
+    case $word in
+        *.rs | *.toml) echo \"$word rust\";;
+        [A-Z]*) echo \"$word upper\";&
+        \"$star\") echo \"$word star\";;&
+        $star) echo \"$word any\";;
+    esac
+done
+case x in y) echo no;; esac; echo \"none $?\"
+case ab in (a) ;; ('a'*) (exit 4);; esac; echo \"status $?\"";
+        assert_eq!(
+            output_of(source),
+            (0,String::from("main.rs rust\nMakefile upper\nMakefile star\nMakefile any\n* star\n* any\nx any\nnone 0\nstatus 4\n"))
+        );
+    }
+
+    #[test]
+    fn test_case_fallthrough_at_the_edges() {
+        let source = "case a in a) echo a;& b) echo b;& c) echo c;; esac
+case a in a) echo last;& esac; echo \"last $?\"
+case b in a) echo no;; b) (exit 3);& esac; echo \"into nothing $?\"
+case a in a) ;& b) (exit 9);; esac; echo \"empty body $?\"
+case ab in a*) echo first;;& b*) echo never;;& *b) echo third;;& x) echo never;; esac; echo \"tested all $?\"
+case ab in a*) (exit 5);;& x) ;; esac; echo \"unmatched after $?\"
+case a in a) echo one;& b) (exit 2);;& *) echo \"star $?\";; esac";
+        // `;&` runs the next body without testing its pattern, `;;&` goes on testing them
+        assert_eq!(
+            output_of(source),
+            (0,String::from("a\nb\nc\nlast\nlast 0\ninto nothing 3\nempty body 9\nfirst\nthird\ntested all 0\nunmatched after 5\none\nstar 2\n"))
+        );
+    }
+
+    #[test]
+    fn test_functions_arguments_locals_and_return() {
+        let source = "greet() { echo \"hello $1 ($#: $@)\"; }
+function show { echo \"$x ${y-unset} $GREETING\"; }
+inner() { local x=inner; show; }
+outer() {
+    local x=outer y
+    inner
+    show
+    for i in 1 2 3; do if [ $i = 2 ]; then return 4; fi; done
+    echo never
+}
+greet world 'and more'
+x=global y=set
+outer; echo \"outer $? $x $y\"
+GREETING=hi show; show
+echo \"args $1\"
+down() { if [ $1 = 0 ]; then return; fi; down $(($1 - 1)); }
+down 100; echo \"down $?\"
+return 3";
+        assert_eq!(
+            output_with_arguments(source,&["outside"]),
+            (1,String::from(
+                "hello world (2: world and more)\n\
+                 inner unset \nouter unset \nouter 4 global set\n\
+                 global set hi\nglobal set \nargs outside\ndown 0\n"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_return_status_and_nested_locals() {
+        let source = "f() { (exit 7); return; }; f; echo \"bare return $?\"
+g() { for i in 1 2; do false; return; done; echo never; }; g; echo \"from a loop $?\"
+h() { return; }; (exit 4); h; echo \"nothing ran $?\"
+x=global
+level2() { echo \"level2 sees $x\"; x=set-by-level2; local x=level2; echo \"level2 local $x\"; }
+level1() { local x=level1; level2; echo \"level1 after $x\"; }
+level1; echo \"global $x\"
+inner() { local y=inner; }; inner; echo \"y ${y-unset}\"";
+        // an assignment without `local` changes the nearest caller's local, not the global
+        assert_eq!(
+            output_of(source),
+            (0,String::from(
+                "bare return 7\nfrom a loop 1\nnothing ran 4\n\
+                 level2 sees level1\nlevel2 local level2\nlevel1 after set-by-level2\nglobal global\ny unset\n"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_function_depth_error_points_at_the_call() {
+        let source = "f() { f; }\nf\n";
+        let program = parse_program(source).unwrap();
+        let Command::Simple(call) = &program.items[1].and_or.first.commands[0] else { panic!() };
+        let mut shell = Shell::new();
+        // as if already that deep, so the call fails without recursing
+        shell.function_depth = MAX_FUNCTION_DEPTH;
+        assert_eq!(shell.execute_program("script.sh",source,&program),1);
+        let function = shell.functions["f"].clone();
+        let err = shell.call_function(&function,&[String::from("f")],&[],call.span).unwrap_err();
+        assert_eq!(
+            shell.diagnostic(&err),
+            "error: f: maximum function nesting level exceeded (256)\n --> script.sh:2:1\n  |\n2 | f\n  | ^"
+        );
+        // the whole way down needs about as much stack as a main thread has in debug builds
+        let forever = std::thread::Builder::new().stack_size(8 << 20)
+            .spawn(|| output_of("forever() { forever; }\nforever; echo \"forever $?\""))
+            .unwrap().join().unwrap();
+        assert_eq!(forever,(0,String::from("forever 1\n")));
+    }
+
+    #[test]
+    fn test_runtime_errors_show_their_source_line() {
+        let source = ":\n: ${missing:?no value}\n";
+        let program = parse_program(source).unwrap();
+        let Command::Simple(command) = &program.items[1].and_or.first.commands[0] else { panic!() };
+        let mut shell = Shell::new();
+        let err = shell.expand_word(&command.words[1]).unwrap_err();
+        assert_eq!(shell.diagnostic(&err),"hsh: missing: no value");
+        assert_eq!(shell.execute_program("script.sh",source,&program),1);
+        assert_eq!(
+            shell.diagnostic(&err),
+            format!("error: missing: no value\n --> script.sh:2:3\n  |\n2 | : ${{missing:?no value}}\n  |   {}","^".repeat(20))
+        );
+    }
+
+    #[test]
+    fn test_unsupported_compound_commands_fail_with_status_2() {
+        for source in ["true\nselect x in a; do :; done\n", "true\n[[ -n x ]]\n"]{
+            let program = parse_program(source).unwrap();
+            let mut shell = Shell::new();
+            assert_eq!(shell.execute_program("script.sh",source,&program),2);
+            assert_eq!(shell.last_status,2);
+        }
+        let source = "true\nselect x in a; do :; done\n";
+        let program = parse_program(source).unwrap();
+        let Command::Compound(compound,_) = &program.items[1].and_or.first.commands[0] else { panic!() };
+        let mut shell = Shell::new();
+        shell.execute_program("script.sh",source,&program);
+        let err = ExecutionError::Unsupported { keyword: String::from("select"), span: compound.span };
+        assert_eq!(
+            shell.diagnostic(&err),
+            format!("error: select: not supported yet\n --> script.sh:2:1\n  |\n2 | select x in a; do :; done\n  | {}","^".repeat(25))
+        );
+    }
+}
@@ -0,0 +1,743 @@
+//! Word expansion: turns the words of the AST into the strings commands are given.
+
+use nix::unistd::{User, getuid};
+
+use crate::arithmetic::evaluate;
+use crate::brace::expand_braces;
+use crate::error::ExecutionError;
+use crate::executor::Shell;
+use crate::parser::{ASTExpr, CommandList, Parameter, ParameterExpansion, ParameterOperation, ReplaceMode, ShellWord};
+use crate::glob::expand_pathname;
+use crate::pattern::{self, Pattern};
+use crate::process::process_impl::capture_output;
+use crate::tokenizer::Span;
+use crate::variables::DEFAULT_IFS;
+
+/// Where expanded text ends up.
+#[derive(Debug,Clone,Copy,Default)]
+struct Context{
+    /// Inside double quotes.
+    quoted:bool,
+    /// Unquoted substitutions are split into separate fields, as for command words.
+    split:bool,
+    /// The word being expanded, for errors to point at.
+    span:Span
+}
+
+impl Context{
+    /// Plain text, neither quoted nor split, in the word at `span`.
+    fn at(span:Span)->Self{
+        Context { span, ..Context::default() }
+    }
+}
+
+/// One expanded field, along with the same text as a pattern in which whatever came from
+/// quotes is escaped so it matches literally.
+#[derive(Debug,Default)]
+struct Field{
+    text:String,
+    pattern:String
+}
+
+/// The fields a word expands to, built up a segment at a time.
+#[derive(Debug,Default)]
+struct Fields{
+    done:Vec<Field>,
+    current:Field,
+    /// Whether there is a current field even if it is empty, as after `""`.
+    started:bool,
+    /// The characters unquoted substitutions are split at, `$IFS`.
+    separators:String,
+    /// Set when IFS whitespace just ended a field, so a following non-whitespace
+    /// separator belongs to the same delimiter.
+    after_whitespace:bool
+}
+
+impl Fields{
+    fn new(separators:&str)->Self{
+        Fields { separators: separators.to_string(), ..Fields::default() }
+    }
+
+    /// Adds `text`, which is `literal` when it came from quotes or the source rather than
+    /// from an unquoted substitution or wildcard.
+    fn push(&mut self,text:&str,literal:bool){
+        self.current.text.push_str(text);
+        match literal {
+            true => self.current.pattern.push_str(&pattern::escape(text)),
+            false => self.current.pattern.push_str(text)
+        }
+        self.started = true;
+        self.after_whitespace = false;
+    }
+
+    /// Adds text that is split into fields at the characters of `$IFS`. Runs of IFS
+    /// whitespace separate fields and are dropped at either end. Any other separator ends
+    /// a field even when it is empty, as in `a::b`.
+    fn push_split(&mut self,text:&str){
+        for char in text.chars(){
+            if !self.separators.contains(char){
+                self.push(&char.to_string(),false);
+            }
+            else if matches!(char,' ' | '\t' | '\n'){
+                if self.started{
+                    self.end_field();
+                    self.after_whitespace = true;
+                }
+            }
+            else{
+                if self.started{
+                    self.end_field();
+                }
+                else if !self.after_whitespace{
+                    self.done.push(Field::default());
+                }
+                self.after_whitespace = false;
+            }
+        }
+    }
+
+    /// Ends the current field even if nothing started it, as between the fields of
+    /// `"$@"`.
+    fn break_field(&mut self){
+        self.started = true;
+        self.end_field();
+    }
+
+    fn end_field(&mut self){
+        if self.started{
+            self.done.push(std::mem::take(&mut self.current));
+            self.started = false;
+        }
+    }
+
+    fn finish(mut self)->Vec<Field>{
+        self.end_field();
+        self.done
+    }
+}
+
+impl Shell{
+    /// Expands a word into the string it stands for, without splitting it.
+    pub(crate) fn expand_word(&mut self,word:&ShellWord)->Result<String,ExecutionError>{
+        let segments = self.expand_tildes(&word.segments,false);
+        Ok(self.expand_segments(&segments,Context::at(word.span))?.text)
+    }
+
+    /// Expands the value of an assignment, in which a `~` following a `:` starts a tilde
+    /// prefix too, as in `PATH=~/bin:~/.local/bin`.
+    pub(crate) fn expand_assignment(&mut self,word:&ShellWord)->Result<String,ExecutionError>{
+        let segments = self.expand_tildes(&word.segments,true);
+        Ok(self.expand_segments(&segments,Context::at(word.span))?.text)
+    }
+
+    /// Expands a `case` pattern into the pattern the subject is matched against, in which
+    /// quoted characters only match themselves.
+    pub(crate) fn expand_case_pattern(&mut self,word:&ShellWord)->Result<Pattern,ExecutionError>{
+        let segments = self.expand_tildes(&word.segments,false);
+        self.expand_pattern(&segments,word.span)
+    }
+
+    /// Expands a word into the fields it makes up as a command word: brace expanded, then
+    /// split, then each field with wildcards replaced by the paths it matches. None at all
+    /// for a word that was only unquoted substitutions of nothing.
+    pub(crate) fn expand_fields(&mut self,word:&ShellWord)->Result<Vec<String>,ExecutionError>{
+        let mut expanded = vec![];
+        for segments in expand_braces(&word.segments){
+            let segments = self.expand_tildes(&segments,false);
+            let mut fields = Fields::new(self.variables.get("IFS").unwrap_or(DEFAULT_IFS));
+            for segment in &segments{
+                self.expand_segment(segment,&mut fields,Context { split: true, ..Context::at(word.span) })?;
+            }
+            for field in fields.finish(){
+                expanded.extend(self.expand_pathname(field,word.span)?);
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Replaces tilde prefixes with the directories they stand for. A prefix is a `~` that
+    /// starts the word, or follows a `:` in an assignment, up to the next `/` (or `:`) when
+    /// nothing in between is quoted or expanded. Directories come out single quoted, so
+    /// they are neither split nor matched as patterns.
+    fn expand_tildes(&self,segments:&[ASTExpr],assignment:bool)->Vec<ASTExpr>{
+        let mut expanded = vec![];
+        let mut index = 0;
+        while index < segments.len(){
+            let starts_prefix = index == 0
+                || assignment && matches!(&segments[index - 1],ASTExpr::Literal(text) if text.ends_with(':'));
+            if segments[index] != ASTExpr::Tilde || !starts_prefix{
+                expanded.push(segments[index].clone());
+                index += 1;
+                continue;
+            }
+            // the login name and what follows it in the same literal, None when the name
+            // runs into something quoted or expanded
+            let prefix = match segments.get(index + 1) {
+                None => Some(("","")),
+                Some(ASTExpr::Literal(text)) => match text.find(|char| char == '/' || assignment && char == ':') {
+                    Some(end) => Some(text.split_at(end)),
+                    None if index + 2 == segments.len() => Some((text.as_str(),"")),
+                    None => None
+                },
+                Some(_) => None
+            };
+            let Some(((_,rest),directory)) = prefix.and_then(|prefix| Some((prefix,self.tilde_directory(prefix.0)?))) else {
+                expanded.push(ASTExpr::Tilde);
+                index += 1;
+                continue;
+            };
+            expanded.push(ASTExpr::SingleQuoted(directory));
+            if !rest.is_empty(){
+                expanded.push(ASTExpr::Literal(rest.to_string()));
+            }
+            index += if segments.get(index + 1).is_some() { 2 } else { 1 };
+        }
+        expanded
+    }
+
+    /// What `~name` stands for: the home directory of the user `name`, or without a name
+    /// `$HOME`. `~+` is `$PWD` and `~-` is `$OLDPWD`.
+    fn tilde_directory(&self,name:&str)->Option<String>{
+        let user = match name {
+            "+" => return self.variables.get("PWD").map(str::to_string),
+            "-" => return self.variables.get("OLDPWD").map(str::to_string),
+            "" => match self.variables.get("HOME") {
+                Some(home) => return Some(home.to_string()),
+                None => User::from_uid(getuid())
+            },
+            name => User::from_name(name)
+        };
+        Some(user.ok()??.dir.to_string_lossy().into_owned())
+    }
+
+    /// Expands segments that form a pattern, such as the one in `${name#pattern}`.
+    fn expand_pattern(&mut self,segments:&[ASTExpr],span:Span)->Result<Pattern,ExecutionError>{
+        let field = self.expand_segments(segments,Context::at(span))?;
+        Ok(Pattern::parse(&field.pattern,self.options.extglob))
+    }
+
+    /// The locale pathname expansion sorts in: the first of `LC_ALL`, `LC_COLLATE` and
+    /// `LANG` that is set and not empty, or C.
+    fn collation_locale(&self)->&str{
+        ["LC_ALL","LC_COLLATE","LANG"].into_iter()
+            .filter_map(|name| self.variables.get(name))
+            .find(|locale| !locale.is_empty())
+            .unwrap_or("C")
+    }
+
+    /// The paths a field's pattern matches. A field without wildcards stays as it is, as
+    /// does one that matches nothing unless nullglob or failglob is on.
+    fn expand_pathname(&self,field:Field,span:Span)->Result<Vec<String>,ExecutionError>{
+        if !Pattern::parse(&field.pattern,self.options.extglob).has_wildcards(){
+            return Ok(vec![field.text]);
+        }
+        let paths = expand_pathname(&field.pattern,&self.options,self.collation_locale());
+        match paths.is_empty() {
+            false => Ok(paths),
+            true if self.options.failglob => Err(ExecutionError::NoMatch { pattern: field.text, span }),
+            true if self.options.nullglob => Ok(vec![]),
+            true => Ok(vec![field.text])
+        }
+    }
+
+    fn expand_segments(&mut self,segments:&[ASTExpr],context:Context)->Result<Field,ExecutionError>{
+        let mut fields = Fields::default();
+        for segment in segments{
+            self.expand_segment(segment,&mut fields,context)?;
+        }
+        Ok(fields.current)
+    }
+
+    fn expand_segment(&mut self,segment:&ASTExpr,fields:&mut Fields,context:Context)->Result<(),ExecutionError>{
+        // unquoted substitutions are split when making fields, and are patterns as they
+        // stand, as in `${name#$prefix}`
+        let push_substituted = |fields:&mut Fields,value:&str| match context.split && !context.quoted {
+            true => fields.push_split(value),
+            false => fields.push(value,context.quoted)
+        };
+        match segment {
+            ASTExpr::Literal(text) | ASTExpr::SingleQuoted(text) => fields.push(text,true),
+            ASTExpr::DoubleQuoted(inner) => {
+                // `""` is an empty field rather than none, but `"$@"` without positional
+                // parameters is no field at all
+                if !inner.iter().any(is_all_positionals){
+                    fields.push("",true);
+                }
+                for segment in inner{
+                    self.expand_segment(segment,fields,Context { quoted: true, ..context })?;
+                }
+            },
+            ASTExpr::Escaped(char) => fields.push(&char.to_string(),true),
+            ASTExpr::GlobChar(char) => fields.push(&char.to_string(),false),
+            ASTExpr::Tilde => fields.push("~",true),
+            ASTExpr::SubstituteVar(ParameterExpansion { parameter: Parameter::Special(char @ ('@' | '*')), operation: None })
+                if context.split && (*char == '@' || !context.quoted) => {
+                // one field per positional parameter, each split again when unquoted
+                for (index,parameter) in self.positional_parameters.iter().enumerate(){
+                    match context.quoted {
+                        true => {
+                            if index > 0{
+                                fields.break_field();
+                            }
+                            fields.push(parameter,true);
+                        },
+                        false => {
+                            if index > 0{
+                                fields.end_field();
+                            }
+                            fields.push_split(parameter);
+                        }
+                    }
+                }
+            },
+            ASTExpr::SubstituteVar(expansion) => {
+                let value = self.expand_parameter(expansion,context)?;
+                push_substituted(fields,&value);
+            },
+            ASTExpr::Arithmetic(parts) => {
+                let expression = self.expand_segments(parts,Context::at(context.span))?.text;
+                let value = self.evaluate_arithmetic(expression,context.span)?.to_string();
+                push_substituted(fields,&value);
+            },
+            ASTExpr::CommandSubstitution(list) => {
+                let output = self.substitute_command(list);
+                push_substituted(fields,&output);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the list of a command substitution in a subshell and gives what it wrote to
+    /// stdout, less trailing newlines. `$?` becomes the subshell's status.
+    fn substitute_command(&mut self,list:&CommandList)->String{
+        let captured = capture_output(|| {
+            self.interactive = false;
+            // spans in the list point into the substitution's own text
+            self.source = None;
+            self.execute_list(list)
+        });
+        let (output,status) = match captured {
+            Ok(captured) => captured,
+            Err(err) => {
+                eprintln!("hsh: command substitution: {}",err.desc());
+                (vec![],1)
+            }
+        };
+        self.last_status = status;
+        self.substitution_status = Some(status);
+        let mut output = String::from_utf8_lossy(&output).into_owned();
+        output.truncate(output.trim_end_matches('\n').len());
+        output
+    }
+
+    fn evaluate_arithmetic(&mut self,expression:String,span:Span)->Result<i64,ExecutionError>{
+        evaluate(&expression,&mut self.variables).map_err(|source| ExecutionError::Arithmetic { expression, source, span })
+    }
+
+    /// Expands `${...}`, applying its operation to the parameter's value.
+    fn expand_parameter(&mut self,expansion:&ParameterExpansion,context:Context)->Result<String,ExecutionError>{
+        let parameter = &expansion.parameter;
+        let value = self.parameter_value(parameter);
+        let Some(operation) = &expansion.operation else {
+            return Ok(value.unwrap_or_default());
+        };
+        // words after `:-` and the like are expanded as though they stood where the
+        // expansion does
+        let word_context = Context { split: false, ..context };
+        let missing = |check_null:bool| value.as_ref().is_none_or(|value| check_null && value.is_empty());
+        let value = match operation {
+            ParameterOperation::Length => match parameter {
+                Parameter::Special('@' | '*') => self.positional_parameters.len().to_string(),
+                _ => value.unwrap_or_default().chars().count().to_string()
+            },
+            ParameterOperation::Default { check_null, word } => match missing(*check_null) {
+                true => self.expand_segments(word,word_context)?.text,
+                false => value.unwrap_or_default()
+            },
+            ParameterOperation::Assign { check_null, word } => match missing(*check_null) {
+                true => {
+                    let Parameter::Named(name) = parameter else {
+                        return Err(ExecutionError::CannotAssign { name: parameter.to_string(), span: context.span });
+                    };
+                    let word = self.expand_segments(word,word_context)?.text;
+                    self.variables.set(name,&word).map_err(|source| ExecutionError::Variable { source, span: context.span })?;
+                    self.variables.get(name).unwrap_or_default().to_string()
+                },
+                false => value.unwrap_or_default()
+            },
+            ParameterOperation::Error { check_null, word } => match missing(*check_null) {
+                true => {
+                    let mut message = self.expand_segments(word,word_context)?.text;
+                    if message.is_empty(){
+                        message = String::from("parameter null or not set");
+                    }
+                    return Err(ExecutionError::UnsetParameter { name: parameter.to_string(), message, span: context.span });
+                },
+                false => value.unwrap_or_default()
+            },
+            ParameterOperation::Alternative { check_null, word } => match missing(*check_null) {
+                true => String::from(""),
+                false => self.expand_segments(word,word_context)?.text
+            },
+            ParameterOperation::RemovePrefix { longest, pattern } => {
+                let pattern = self.expand_pattern(pattern,context.span)?;
+                remove_prefix(&value.unwrap_or_default(),&pattern,*longest)
+            },
+            ParameterOperation::RemoveSuffix { longest, pattern } => {
+                let pattern = self.expand_pattern(pattern,context.span)?;
+                remove_suffix(&value.unwrap_or_default(),&pattern,*longest)
+            },
+            ParameterOperation::Substring { offset, length } => {
+                let offset = self.expand_segments(offset,Context::at(context.span))?.text;
+                let offset = self.evaluate_arithmetic(offset,context.span)?;
+                let length = match length {
+                    Some(length) => {
+                        let length = self.expand_segments(length,Context::at(context.span))?.text;
+                        Some(self.evaluate_arithmetic(length,context.span)?)
+                    },
+                    None => None
+                };
+                substring(&value.unwrap_or_default(),offset,length)
+            },
+            ParameterOperation::Replace { mode, pattern, replacement } => {
+                let pattern = self.expand_pattern(pattern,context.span)?;
+                let replacement = self.expand_segments(replacement,word_context)?.text;
+                replace(&value.unwrap_or_default(),&pattern,&replacement,*mode)
+            },
+            ParameterOperation::ChangeCase { upper, all, pattern } => {
+                let mut pattern = self.expand_pattern(pattern,context.span)?;
+                if pattern.is_empty(){
+                    pattern = Pattern::parse("?",false);
+                }
+                change_case(&value.unwrap_or_default(),&pattern,*upper,*all)
+            }
+        };
+        Ok(value)
+    }
+
+    /// The value of a parameter, None when it is unset.
+    pub(crate) fn parameter_value(&self,parameter:&Parameter)->Option<String>{
+        match parameter {
+            Parameter::Named(name) => self.variables.get(name).map(str::to_string),
+            Parameter::Positional(position) => self.positional_parameters.get(position.checked_sub(1)?).cloned(),
+            Parameter::Special('?') => Some(self.last_status.to_string()),
+            Parameter::Special('#') => Some(self.positional_parameters.len().to_string()),
+            Parameter::Special('@') => Some(self.positional_parameters.join(" ")),
+            // joined with the first character of `$IFS`, nothing when it is empty
+            Parameter::Special('*') => {
+                let separator = self.variables.get("IFS").unwrap_or(DEFAULT_IFS).chars().next();
+                Some(self.positional_parameters.join(&separator.map(String::from).unwrap_or_default()))
+            },
+            Parameter::Special('$') => Some(self.shell_pid.to_string()),
+            Parameter::Special('!') => self.last_background_pid.map(|pid| pid.to_string()),
+            Parameter::Special('-') => Some(String::from(if self.interactive { "i" } else { "" })),
+            Parameter::Special('0') => Some(self.shell_name.clone()),
+            Parameter::Special(_) => None
+        }
+    }
+}
+
+/// Whether `segment` is a plain `$@`, which expands to one field per positional parameter
+/// even in double quotes.
+fn is_all_positionals(segment:&ASTExpr)->bool{
+    matches!(segment,ASTExpr::SubstituteVar(ParameterExpansion { parameter: Parameter::Special('@'), operation: None }))
+}
+
+fn matches(pattern:&Pattern,chars:&[char])->bool{
+    pattern.matches(&chars.iter().collect::<String>())
+}
+
+/// `${name#pattern}` and `${name##pattern}`
+fn remove_prefix(value:&str,pattern:&Pattern,longest:bool)->String{
+    let chars:Vec<char> = value.chars().collect();
+    let mut ends:Vec<usize> = (0..=chars.len()).collect();
+    if longest{
+        ends.reverse();
+    }
+    match ends.into_iter().find(|end| matches(pattern,&chars[..*end])) {
+        Some(end) => chars[end..].iter().collect(),
+        None => value.to_string()
+    }
+}
+
+/// `${name%pattern}` and `${name%%pattern}`
+fn remove_suffix(value:&str,pattern:&Pattern,longest:bool)->String{
+    let chars:Vec<char> = value.chars().collect();
+    let mut starts:Vec<usize> = (0..=chars.len()).collect();
+    if !longest{
+        starts.reverse();
+    }
+    match starts.into_iter().find(|start| matches(pattern,&chars[*start..])) {
+        Some(start) => chars[..start].iter().collect(),
+        None => value.to_string()
+    }
+}
+
+/// `${name:offset:length}`. A negative offset counts from the end, as does a negative
+/// length, which then marks where the substring stops.
+fn substring(value:&str,offset:i64,length:Option<i64>)->String{
+    let chars:Vec<char> = value.chars().collect();
+    let count = chars.len() as i64;
+    let start = if offset < 0 { count + offset } else { offset };
+    if start < 0 || start > count{
+        return String::from("");
+    }
+    let end = match length {
+        None => count,
+        Some(length) if length < 0 => count + length,
+        Some(length) => start.saturating_add(length).min(count)
+    };
+    if end <= start{
+        return String::from("");
+    }
+    chars[start as usize..end as usize].iter().collect()
+}
+
+/// The end of the longest match of `pattern` starting at `start`.
+fn longest_match(pattern:&Pattern,chars:&[char],start:usize)->Option<usize>{
+    (start..=chars.len()).rev().find(|end| matches(pattern,&chars[start..*end]))
+}
+
+/// `${name/pattern/replacement}` and its `//`, `/#` and `/%` forms. Matches are the
+/// longest possible. An empty pattern leaves the value alone, except that the anchored
+/// forms match the empty prefix or suffix.
+fn replace(value:&str,pattern:&Pattern,replacement:&str,mode:ReplaceMode)->String{
+    if pattern.is_empty() && matches!(mode,ReplaceMode::First | ReplaceMode::All){
+        return value.to_string();
+    }
+    let chars:Vec<char> = value.chars().collect();
+    let rest = |from:usize| chars[from..].iter().collect::<String>();
+    match mode {
+        ReplaceMode::Prefix => match longest_match(pattern,&chars,0) {
+            Some(end) => format!("{replacement}{}",rest(end)),
+            None => value.to_string()
+        },
+        ReplaceMode::Suffix => match (0..=chars.len()).find(|start| matches(pattern,&chars[*start..])) {
+            Some(start) => format!("{}{replacement}",chars[..start].iter().collect::<String>()),
+            None => value.to_string()
+        },
+        ReplaceMode::First | ReplaceMode::All => {
+            let mut replaced = String::from("");
+            let mut index = 0;
+            while index < chars.len(){
+                match longest_match(pattern,&chars,index) {
+                    // an empty match would replace between every character
+                    Some(end) if end > index => {
+                        replaced.push_str(replacement);
+                        index = end;
+                        if mode == ReplaceMode::First{
+                            replaced.push_str(&rest(index));
+                            return replaced;
+                        }
+                    },
+                    _ => {
+                        replaced.push(chars[index]);
+                        index += 1;
+                    }
+                }
+            }
+            replaced
+        }
+    }
+}
+
+/// `${name^pattern}`, `${name^^pattern}`, `${name,pattern}` and `${name,,pattern}`:
+/// changes the case of the first character, or of every one, that matches `pattern`.
+fn change_case(value:&str,pattern:&Pattern,upper:bool,all:bool)->String{
+    let mut changed = String::from("");
+    for (index,char) in value.chars().enumerate(){
+        if (all || index == 0) && pattern.matches(&char.to_string()){
+            match upper {
+                true => changed.extend(char.to_uppercase()),
+                false => changed.extend(char.to_lowercase())
+            }
+        }
+        else{
+            changed.push(char);
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests{
+    use crate::executor::Shell;
+    use crate::parser::{Command, parse_program};
+
+    /// Expands the words of the simple command in `source`.
+    fn expand(shell:&mut Shell,source:&str)->Vec<String>{
+        let program = parse_program(source).unwrap();
+        let Command::Simple(command) = &program.items[0].and_or.first.commands[0] else { panic!() };
+        command.words.iter().map(|word| shell.expand_word(word).unwrap()).collect()
+    }
+
+    /// The fields the words of the simple command in `source` make up.
+    fn fields(shell:&mut Shell,source:&str)->Vec<String>{
+        let program = parse_program(source).unwrap();
+        let Command::Simple(command) = &program.items[0].and_or.first.commands[0] else { panic!() };
+        command.words.iter().flat_map(|word| shell.expand_fields(word).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_variables_and_special_parameters() {
+        let mut shell = Shell::new();
+        shell.variables.set("name","world").unwrap();
+        shell.positional_parameters = vec![String::from("a"),String::from("b")];
+        shell.last_status = 3;
+        assert_eq!(
+            expand(&mut shell,"echo hello-$name \"${name}!\" '$name' $missing. $? $# $2 $@"),
+            vec!["echo","hello-world","world!","$name",".","3","2","b","a b"]
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_expansion_uses_variables() {
+        let mut shell = Shell::new();
+        shell.variables.set("x","4").unwrap();
+        assert_eq!(expand(&mut shell,"echo $((x * 2)) $(( $x + 1 )) $((y = x ** 2))"),vec!["echo","8","5","16"]);
+        assert_eq!(shell.variables.get("y"),Some("16"));
+        let program = parse_program("echo $((1 / 0))").unwrap();
+        let Command::Simple(command) = &program.items[0].and_or.first.commands[0] else { panic!() };
+        assert_eq!(shell.expand_word(&command.words[1]).unwrap_err().to_string(),"1 / 0: division by 0");
+    }
+
+    #[test]
+    fn test_defaults_and_alternatives() {
+        let mut shell = Shell::new();
+        shell.variables.set("empty","").unwrap();
+        shell.variables.set("set","value").unwrap();
+        assert_eq!(
+            expand(&mut shell,"echo ${unset-a} ${empty-b}. ${empty:-c} ${set:-d} ${set:+\"$set\"!} ${unset+e}. ${#set}"),
+            vec!["echo","a",".","c","value","value!",".","5"]
+        );
+        assert_eq!(expand(&mut shell,"echo ${new:=assigned} $new"),vec!["echo","assigned","assigned"]);
+
+        let program = parse_program("echo ${unset:?} ${empty?is empty} ${unset?missing $set}").unwrap();
+        let Command::Simple(command) = &program.items[0].and_or.first.commands[0] else { panic!() };
+        assert_eq!(shell.expand_word(&command.words[1]).unwrap_err().to_string(),"unset: parameter null or not set");
+        assert_eq!(shell.expand_word(&command.words[2]).unwrap(),"");
+        assert_eq!(shell.expand_word(&command.words[3]).unwrap_err().to_string(),"unset: missing value");
+    }
+
+    #[test]
+    fn test_pattern_removal_and_replacement() {
+        let mut shell = Shell::new();
+        shell.variables.set("path","/usr/local/lib.tar.gz").unwrap();
+        shell.variables.set("star","*").unwrap();
+        assert_eq!(
+            expand(&mut shell,"echo ${path#*/} ${path##*/} ${path%.*} ${path%%.*} ${path#\"*\"}"),
+            vec!["echo","usr/local/lib.tar.gz","lib.tar.gz","/usr/local/lib.tar","/usr/local/lib","/usr/local/lib.tar.gz"]
+        );
+        assert_eq!(
+            expand(&mut shell,"echo ${path/l/L} ${path//l/L} ${path/#\\/usr/~} ${path/%gz/bz2} ${path//[aeiou]}"),
+            vec!["echo","/usr/Local/lib.tar.gz","/usr/LocaL/Lib.tar.gz","~/local/lib.tar.gz","/usr/local/lib.tar.bz2","/sr/lcl/lb.tr.gz"]
+        );
+        // a quoted parameter matches literally, an unquoted one is a pattern
+        assert_eq!(expand(&mut shell,"echo ${path##$star}. ${path#\"$star\"}"),vec!["echo",".","/usr/local/lib.tar.gz"]);
+        // an empty pattern only matches when anchored
+        shell.variables.set("word","hello").unwrap();
+        assert_eq!(expand(&mut shell,"echo ${word/#/X} ${word/%/Y} ${word//} ${word/}"),vec!["echo","Xhello","helloY","hello","hello"]);
+    }
+
+    #[test]
+    fn test_substrings_and_case() {
+        let mut shell = Shell::new();
+        shell.variables.set("word","hello world").unwrap();
+        assert_eq!(
+            expand(&mut shell,"echo ${word:6} ${word:0:5} ${word: -5:3} ${word:1:-1} ${word:20}."),
+            vec!["echo","world","hello","wor","ello worl","."]
+        );
+        assert_eq!(expand(&mut shell,"echo ${word:1:9223372036854775807}"),vec!["echo","ello world"]);
+        assert_eq!(
+            expand(&mut shell,"echo ${word^} ${word^^} ${word^^[lo]} ${word,,} X${word,}"),
+            vec!["echo","Hello world","HELLO WORLD","heLLO wOrLd","hello world","Xhello world"]
+        );
+    }
+
+    #[test]
+    fn test_field_splitting_with_ifs() {
+        let mut shell = Shell::new();
+        shell.variables.set("spaced","  a \t b  ").unwrap();
+        shell.variables.set("empty","").unwrap();
+        assert_eq!(fields(&mut shell,"echo $spaced x$spaced\"$spaced\" $empty \"\" $empty"),vec!["echo","a","b","x","a","b","  a \t b  ",""]);
+
+        shell.variables.set("IFS",":").unwrap();
+        shell.variables.set("list","a::b:").unwrap();
+        assert_eq!(fields(&mut shell,"echo $list"),vec!["echo","a","","b"]);
+        shell.variables.set("IFS"," :").unwrap();
+        shell.variables.set("list"," a : b::c ").unwrap();
+        assert_eq!(fields(&mut shell,"echo $list $((12))"),vec!["echo","a","b","","c","12"]);
+        shell.variables.set("IFS","").unwrap();
+        assert_eq!(fields(&mut shell,"echo $list"),vec!["echo"," a : b::c "]);
+    }
+
+    #[test]
+    fn test_positional_parameters_as_fields() {
+        let mut shell = Shell::new();
+        assert_eq!(fields(&mut shell,"echo \"$@\" $@ \"$*\""),vec!["echo",""]);
+        shell.positional_parameters = vec![String::from("1 2"),String::from(""),String::from("3")];
+        assert_eq!(fields(&mut shell,"echo \"$@\""),vec!["echo","1 2","","3"]);
+        assert_eq!(fields(&mut shell,"echo \"x$@y\""),vec!["echo","x1 2","","3y"]);
+        assert_eq!(fields(&mut shell,"echo $@ a$*"),vec!["echo","1","2","3","a1","2","3"]);
+        assert_eq!(fields(&mut shell,"echo \"$*\""),vec!["echo","1 2  3"]);
+        shell.variables.set("IFS","-").unwrap();
+        assert_eq!(fields(&mut shell,"echo \"$*\""),vec!["echo","1 2--3"]);
+    }
+
+    #[test]
+    fn test_pathname_expansion_of_unquoted_wildcards() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.rs","b.rs","*.rs"]{
+            std::fs::File::create(dir.path().join(name)).unwrap();
+        }
+        let dir = dir.path().display().to_string();
+        let mut shell = Shell::new();
+        shell.variables.set("wild","*.rs").unwrap();
+        assert_eq!(
+            fields(&mut shell,&format!("echo {dir}/*.rs {dir}/\"*\".rs {dir}/$wild \"{dir}/$wild\" {dir}/*.none")),
+            vec![
+                String::from("echo"),
+                format!("{dir}/*.rs"),format!("{dir}/a.rs"),format!("{dir}/b.rs"),
+                format!("{dir}/*.rs"),
+                format!("{dir}/*.rs"),format!("{dir}/a.rs"),format!("{dir}/b.rs"),
+                format!("{dir}/*.rs"),
+                format!("{dir}/*.none")
+            ]
+        );
+        shell.options.nullglob = true;
+        assert_eq!(fields(&mut shell,&format!("echo {dir}/*.none")),vec!["echo"]);
+        shell.options.failglob = true;
+        let program = parse_program(&format!("echo {dir}/*.none")).unwrap();
+        let Command::Simple(command) = &program.items[0].and_or.first.commands[0] else { panic!() };
+        assert_eq!(shell.expand_fields(&command.words[1]).unwrap_err().to_string(),format!("no match: {dir}/*.none"));
+    }
+
+    #[test]
+    fn test_brace_expansion_comes_before_parameters() {
+        let mut shell = Shell::new();
+        shell.variables.set("braces","{1,2}").unwrap();
+        shell.variables.set("name","x").unwrap();
+        assert_eq!(
+            fields(&mut shell,"echo $braces {$name,y}-{01..2} \"{a,b}\""),
+            vec!["echo","{1,2}","x-01","x-02","y-01","y-02","{a,b}"]
+        );
+    }
+
+    #[test]
+    fn test_tilde_prefixes() {
+        let mut shell = Shell::new();
+        shell.variables.set("HOME","/home/me").unwrap();
+        shell.variables.set("PWD","/work").unwrap();
+        shell.variables.set("OLDPWD","/before").unwrap();
+        assert_eq!(
+            fields(&mut shell,"echo ~ ~/src \"~\" '~'/x ~+/a ~- x~ ~root/y ~no-such-user-hsh/z"),
+            vec!["echo","/home/me","/home/me/src","~","~/x","/work/a","/before","x~","/root/y","~no-such-user-hsh/z"]
+        );
+        let program = parse_program("PATH=~/bin:~+:/usr/bin~ x=a:~").unwrap();
+        let Command::Simple(command) = &program.items[0].and_or.first.commands[0] else { panic!() };
+        assert_eq!(shell.expand_assignment(&command.assignments[0].value).unwrap(),"/home/me/bin:/work:/usr/bin~");
+        assert_eq!(shell.expand_assignment(&command.assignments[1].value).unwrap(),"a:/home/me");
+        shell.variables.set("HOME","/spaced home").unwrap();
+        assert_eq!(fields(&mut shell,"echo ~/*"),vec!["echo","/spaced home/*"]);
+    }
+}
@@ -1,4 +1,4 @@
-///All sys calls implementations related to files and directories.
+//!All sys calls implementations related to files and directories.
 
 
 
@@ -7,6 +7,7 @@
 pub mod syscalls {
     use std::path::{Path, PathBuf};
 
+    use nix::errno::Errno;
     use nix::unistd::{getcwd,chdir};
 
     use crate::error::FsError;
@@ -20,20 +21,33 @@ pub mod syscalls {
             Ok(path)=>Ok(path),
             
             Err(errno)=>{
-                Err(FsError::DisplayCwdError {errno: errno })
+                Err(FsError::DisplayCwdError {errno })
             }
         }
     }
 
     pub fn change_working_dir_impl(path:&Path)->Result<(), FsError>{
         match chdir(path) {
-            Ok(output_path)=>Ok(()),
+            Ok(())=>Ok(()),
             Err(errno)=>{
                 Err(FsError::ChangeCwdError { errno })
             }
         }
     }
 
+    /// Names of the entries of the directory at `path`, without `.` and `..`. Names that
+    /// are not valid UTF-8 are left out.
+    pub fn read_dir_impl(path:&Path)->Result<Vec<String>, FsError>{
+        match std::fs::read_dir(path) {
+            Ok(entries)=>{
+                Ok(entries.filter_map(|entry| entry.ok()?.file_name().into_string().ok()).collect())
+            },
+            Err(err)=>{
+                Err(FsError::ReadDirError { errno: Errno::from_raw(err.raw_os_error().unwrap_or(0)) })
+            }
+        }
+    }
+
 
 }
 
@@ -55,7 +69,7 @@ pub mod filesystem_syscallfns_tests{
         #[test]
     pub fn test_error_display() {
         // Create a mock error to test Display output
-        let mock_nix_error = NixError::EACCES;
+        let _mock_nix_error = NixError::EACCES;
         let fs_error = FsError::DisplayCwdError {
             errno: NixError::EACCES,
         };
@@ -78,7 +92,7 @@ pub mod filesystem_syscallfns_tests{
     pub fn test_error_source_chain() {
         use std::error::Error;
 
-        let mock_nix_error = NixError::ENOTDIR;
+        let _mock_nix_error = NixError::ENOTDIR;
         let fs_error = FsError::DisplayCwdError {
             errno: NixError::ENOTDIR,
         };
@@ -0,0 +1,237 @@
+//! Pathname expansion: the files a pattern in an unquoted word stands for.
+
+use std::ffi::CString;
+use std::path::Path;
+use std::ptr::null_mut;
+
+use nix::libc::{LC_COLLATE_MASK, c_char, freelocale, locale_t, newlocale, size_t};
+
+use crate::fs::syscalls::read_dir_impl;
+use crate::options::ShellOptions;
+use crate::pattern::Pattern;
+
+unsafe extern "C" {
+    // POSIX, but not bound by the libc crate on every target
+    fn strxfrm_l(dest:*mut c_char,src:*const c_char,n:size_t,locale:locale_t)->size_t;
+}
+
+/// The paths `pattern` matches, sorted in the collation order of `locale`, a name such as
+/// `en_US.UTF-8` as `LC_COLLATE` gives it. Each `/`-separated component is matched against
+/// the entries of the directories the previous ones matched. Empty when nothing matches.
+pub fn expand_pathname(pattern:&str,options:&ShellOptions,locale:&str)->Vec<String>{
+    let (prefix,rest) = match pattern.strip_prefix('/') {
+        Some(rest) => ("/",rest),
+        None => ("",pattern)
+    };
+    let components:Vec<&str> = rest.split('/').collect();
+    let mut paths = vec![];
+    expand_components(prefix,&components,options,&mut paths);
+    sort_collated(&mut paths,locale);
+    paths.dedup();
+    paths
+}
+
+/// Sorts `strings` in the collation order of `locale`, or of the C locale, which is byte
+/// order, when the system does not have it. Strings that collate equally go in byte order.
+fn sort_collated(strings:&mut [String],locale:&str){
+    let collation = Collation::new(locale);
+    strings.sort_by_cached_key(|string| (collation.key(string),string.clone()));
+}
+
+/// A locale object for collating, owned by one sort so nothing process-wide changes.
+struct Collation(locale_t);
+
+impl Collation{
+    fn new(name:&str)->Collation{
+        // an empty name would mean the locale the environment gives, not the one asked for
+        let name = CString::new(name).ok().filter(|name| !name.is_empty());
+        // SAFETY: the names are NUL-terminated and outlive the calls, and a null base asks
+        // for a new object rather than changing an existing one
+        let locale = name.map_or(null_mut(),|name| unsafe { newlocale(LC_COLLATE_MASK,name.as_ptr(),null_mut()) });
+        if !locale.is_null(){
+            return Collation(locale);
+        }
+        // SAFETY: as above
+        Collation(unsafe { newlocale(LC_COLLATE_MASK,c"C".as_ptr(),null_mut()) })
+    }
+
+    /// Bytes that compare the way `strcoll` compares `string` in this locale. Byte order
+    /// when there is no locale object or `string` has a NUL.
+    fn key(&self,string:&str)->Vec<u8>{
+        let Ok(source) = CString::new(string) else {
+            return string.as_bytes().to_vec();
+        };
+        if self.0.is_null(){
+            return string.as_bytes().to_vec();
+        }
+        // SAFETY: `source` is NUL-terminated, a zero length lets the null destination go
+        // unwritten, and the locale object is alive until `self` is dropped
+        let length = unsafe { strxfrm_l(null_mut(),source.as_ptr(),0,self.0) };
+        let mut key = vec![0u8;length + 1];
+        // SAFETY: `key` has room for the `length` bytes and the NUL written into it
+        unsafe { strxfrm_l(key.as_mut_ptr().cast(),source.as_ptr(),key.len(),self.0) };
+        key.truncate(length);
+        key
+    }
+}
+
+impl Drop for Collation{
+    fn drop(&mut self){
+        if !self.0.is_null(){
+            // SAFETY: the object came from `newlocale` and nothing uses it after this
+            unsafe { freelocale(self.0) };
+        }
+    }
+}
+
+/// Matches `components` below `prefix`, which is empty for the current directory or ends
+/// with a `/`, adding complete matches to `paths`.
+fn expand_components(prefix:&str,components:&[&str],options:&ShellOptions,paths:&mut Vec<String>){
+    let Some((component,rest)) = components.split_first() else {
+        return;
+    };
+    // `//` in the middle is kept as it was written, a trailing `/` keeps only directories
+    if component.is_empty(){
+        match rest.is_empty() {
+            true if is_directory(prefix) => paths.push(prefix.to_string()),
+            true => {},
+            false => expand_components(&format!("{prefix}/"),rest,options,paths)
+        }
+        return;
+    }
+    let pattern = Pattern::parse(component,options.extglob);
+    if !pattern.has_wildcards(){
+        let path = format!("{prefix}{}",unescape(component));
+        match rest.is_empty() {
+            true if Path::new(&path).symlink_metadata().is_ok() => paths.push(path),
+            true => {},
+            false => expand_components(&format!("{path}/"),rest,options,paths)
+        }
+        return;
+    }
+    if options.globstar && *component == "**"{
+        // any number of directories, none included, or on its own anything below
+        if rest.is_empty(){
+            paths.extend(descendants(prefix,options,false));
+            return;
+        }
+        expand_components(prefix,rest,options,paths);
+        for directory in descendants(prefix,options,true){
+            expand_components(&format!("{directory}/"),rest,options,paths);
+        }
+        return;
+    }
+    for name in entries(prefix,&pattern,options){
+        let path = format!("{prefix}{name}");
+        match rest.is_empty() {
+            true => paths.push(path),
+            false if is_directory(&path) => expand_components(&format!("{path}/"),rest,options,paths),
+            false => {}
+        }
+    }
+}
+
+/// Names in the directory `prefix` that `pattern` matches. A name starting with `.` only
+/// matches a pattern that starts with one too, unless dotglob is on.
+fn entries(prefix:&str,pattern:&Pattern,options:&ShellOptions)->Vec<String>{
+    let directory = if prefix.is_empty() { "." } else { prefix };
+    read_dir_impl(Path::new(directory))
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|name| !name.starts_with('.') || options.dotglob || pattern.starts_with_dot())
+        .filter(|name| pattern.matches(name))
+        .collect()
+}
+
+/// Everything below the directory `prefix` for `**`, or only the directories. Symbolic
+/// links to directories are not followed, so this cannot loop.
+fn descendants(prefix:&str,options:&ShellOptions,directories_only:bool)->Vec<String>{
+    let mut found = vec![];
+    for name in entries(prefix,&Pattern::parse("*",false),options){
+        let path = format!("{prefix}{name}");
+        let directory = Path::new(&path).symlink_metadata().is_ok_and(|metadata| metadata.is_dir());
+        if directory || !directories_only{
+            found.push(path.clone());
+        }
+        if directory{
+            found.extend(descendants(&format!("{path}/"),options,directories_only));
+        }
+    }
+    found
+}
+
+fn is_directory(path:&str)->bool{
+    Path::new(if path.is_empty() { "." } else { path }).is_dir()
+}
+
+/// A pattern component without wildcards as the name it stands for.
+fn unescape(component:&str)->String{
+    let mut name = String::from("");
+    let mut chars = component.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '\\' => name.extend(chars.next()),
+            char => name.push(char)
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::fs::{File, create_dir_all};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_patterns_match_sorted_paths() {
+        let dir = tempdir().unwrap();
+        for path in ["b.rs","a.rs","c.txt",".hidden.rs","src/main.rs","src/lib.rs","src/deep/x.rs"]{
+            let path = dir.path().join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+        let base = format!("{}/",dir.path().display());
+        let glob = |pattern:&str,options:&ShellOptions| -> Vec<String> {
+            expand_pathname(&format!("{base}{pattern}"),options,"C").into_iter()
+                .map(|path| path.strip_prefix(&base).unwrap().to_string())
+                .collect()
+        };
+        let mut options = ShellOptions::default();
+        assert_eq!(glob("*.rs",&options),vec!["a.rs","b.rs"]);
+        assert_eq!(glob("[ab].r?",&options),vec!["a.rs","b.rs"]);
+        assert_eq!(glob(".*.rs",&options),vec![".hidden.rs"]);
+        assert_eq!(glob("*/*.rs",&options),vec!["src/lib.rs","src/main.rs"]);
+        assert_eq!(glob("*/",&options),vec!["src/"]);
+        assert!(glob("*.none",&options).is_empty());
+
+        options.dotglob = true;
+        assert_eq!(glob("*.rs",&options),vec![".hidden.rs","a.rs","b.rs"]);
+        options.dotglob = false;
+        assert_eq!(glob("**/*.rs",&options),vec!["src/lib.rs","src/main.rs"]);
+        options.globstar = true;
+        assert_eq!(glob("**/*.rs",&options),vec!["a.rs","b.rs","src/deep/x.rs","src/lib.rs","src/main.rs"]);
+        assert_eq!(glob("src/**",&options),vec!["src/deep","src/deep/x.rs","src/lib.rs","src/main.rs"]);
+        options.extglob = true;
+        assert_eq!(glob("!(*.rs)",&options),vec!["c.txt","src"]);
+        assert_eq!(glob("@(a|c).*",&options),vec!["a.rs","c.txt"]);
+    }
+
+    #[test]
+    fn test_sorting_follows_the_collation_locale() {
+        let mut names:Vec<String> = ["b","B","a","_x"].map(String::from).to_vec();
+        sort_collated(&mut names,"C");
+        assert_eq!(names,["B","_x","a","b"]);
+        // a locale the system lacks sorts like C
+        names.reverse();
+        sort_collated(&mut names,"no_SUCH.locale");
+        assert_eq!(names,["B","_x","a","b"]);
+        // neither do names libc would take as "use the environment"
+        names.reverse();
+        sort_collated(&mut names,"");
+        assert_eq!(names,["B","_x","a","b"]);
+        names.reverse();
+        sort_collated(&mut names,"en_US\0.UTF-8");
+        assert_eq!(names,["B","_x","a","b"]);
+    }
+}
@@ -1,13 +1,21 @@
-use std::path::Path;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
 
+use crate::executor::{ControlFlow, Shell};
+use crate::error::FsError;
 use crate::fs::syscalls::{change_working_dir_impl, get_cwd_impl};
-mod tokenizer;
+pub mod tokenizer;
 mod process;
-mod parser;
+pub mod parser;
 pub mod error;
-
-use std::ffi::CString;
-use crate::process::process_impl::{redirect_process, IoRedirection};
+pub mod arithmetic;
+pub mod executor;
+mod expansion;
+pub mod variables;
+mod pattern;
+mod glob;
+mod brace;
+pub mod options;
 
 
 
@@ -17,47 +25,208 @@ pub mod fs;
 #[cfg(not(feature = "builtin_access"))]
 pub(crate) mod fs;  // still available internally
 
-pub struct TokenizedOutput<'a>{
-    pub command:&'a str,
-    pub args:Vec<&'a str>
-}
+/// Commands run by the shell itself rather than as a separate program.
+pub const BUILTINS:&[&str] = &["echo","exit","pwd","cd",":","true","false","export","readonly","unset","declare","typeset","shopt","break","continue","read","return","local"];
+
+/// Builtins whose prefix assignments stay in the shell after they run, as POSIX lists them.
+pub const SPECIAL_BUILTINS:&[&str] = &["break",":","continue",".","eval","exec","exit","export","readonly","return","set","shift","times","trap","unset"];
 
-pub fn match_expression(tokens:TokenizedOutput){
-    match tokens.command {
-        "echo"=>{println!("{}",tokens.args.concat())},
+/// Runs the builtin named by `args[0]` and gives its exit status. Output goes through
+/// `std::io::stdout()` so it follows the shell's redirections of fd 1.
+pub fn match_expression(shell:&mut Shell,args:&[String])->i32{
+    let mut stdout = std::io::stdout();
+    match args[0].as_str() {
+        "echo"=>{
+            match writeln!(stdout,"{}",args[1..].join(" ")) {
+                Ok(()) => 0,
+                Err(_) => 1
+            }
+        },
         "exit"=>{
-            println!("bye");
-            std::process::exit(0);
+            let status = match args.get(1) {
+                Some(status) => match status.parse::<i32>() {
+                    Ok(status) => status & 0xff,
+                    Err(_) => {
+                        eprintln!("hsh: exit: {status}: numeric argument required");
+                        2
+                    }
+                },
+                None => shell.last_status
+            };
+            if shell.interactive{
+                println!("bye");
+            }
+            let _ = stdout.flush();
+            std::process::exit(status);
         },
         "pwd"=>{
             match get_cwd_impl() {
                 Ok(path)=>{
-                    println!("{:?}",path.as_path())
+                    match writeln!(stdout,"{}",path.display()) {
+                        Ok(()) => 0,
+                        Err(_) => 1
+                    }
                 },
-                Err(_err)=>{
-                    
+                Err(err)=>{
+                    eprintln!("hsh: pwd: {err}");
+                    1
                 }
             }
         },
-        "cd"=>{
-
-            if tokens.args.len()!=1{
-                eprintln!("Invalid Length");
-                return;
-                // return Err(())
-            }
-            // match change_working_dir_impl(tokens.args) {
-            //     Ok(new_path)=>{
-            //      print!("{}",new_path.)
-            // }
-            // }
-        },
+        "export"=>variables::export_builtin(&mut shell.variables,&args[1..]),
+        "readonly"=>variables::readonly_builtin(&mut shell.variables,&args[1..]),
+        "unset"=>variables::unset_builtin(&mut shell.variables,&args[1..]),
+        "declare" | "typeset"=>variables::declare_builtin(&mut shell.variables,&args[1..]),
+        "shopt"=>options::shopt_builtin(&mut shell.options,&args[1..]),
+        "cd"=>change_directory(shell,&args[1..]),
+        "break" | "continue"=>leave_loops(shell,args),
+        "read"=>variables::read_builtin(&mut shell.variables,&args[1..]),
+        "return"=>return_from_function(shell,args),
+        "local"=>variables::local_builtin(&mut shell.variables,&args[1..]),
+        ":" | "true"=>0,
+        "false"=>1,
         // "check"=>{
         //     println!("Running interactive tests...\n");
         //     run_interactive_tests(&tokens.args);
         // }
-        _ =>{eprintln!("Command not found!")}
+        name =>{
+            eprintln!("hsh: {name}: not a builtin");
+            127
+        }
+    }
+}
+
+/// `break [n]` and `continue [n]`: leave the `n` innermost loops, or all of them when
+/// there are fewer, `continue` going on with the next round of the last one left.
+fn leave_loops(shell:&mut Shell,args:&[String])->i32{
+    let count = match args.get(1) {
+        Some(count) => match count.parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                eprintln!("hsh: {}: {count}: loop count out of range",args[0]);
+                return 1;
+            }
+        },
+        None => 1
+    };
+    if shell.loop_depth == 0{
+        eprintln!("hsh: {}: only meaningful in a `for', `while', or `until' loop",args[0]);
+        return 0;
+    }
+    let count = count.min(shell.loop_depth);
+    shell.control_flow = Some(match args[0].as_str() {
+        "break" => ControlFlow::Break(count),
+        _ => ControlFlow::Continue(count)
+    });
+    0
+}
+
+/// `return [n]`: ends the running function with status `n`, or the status of the last
+/// command without one.
+fn return_from_function(shell:&mut Shell,args:&[String])->i32{
+    if shell.function_depth == 0{
+        eprintln!("hsh: return: can only `return' from a function");
+        return 1;
+    }
+    let status = match args.get(1) {
+        Some(status) => match status.parse::<i32>() {
+            Ok(status) => status & 0xff,
+            Err(_) => {
+                eprintln!("hsh: return: {status}: numeric argument required");
+                2
+            }
+        },
+        None => shell.last_status
+    };
+    shell.control_flow = Some(ControlFlow::Return(status));
+    status
+}
+
+/// `cd [-L|-P] [dir]`: changes to `dir`, `$HOME` without one or `$OLDPWD` for `-`, and
+/// keeps `PWD` and `OLDPWD` up to date. The new `PWD` is worked out from the old one by
+/// dropping `.` and `..` components, unless `-P` asks for the path with symbolic links
+/// resolved.
+fn change_directory(shell:&mut Shell,args:&[String])->i32{
+    let mut physical = false;
+    let mut rest = args;
+    while let Some(flag) = rest.first().filter(|arg| arg.len() > 1 && arg.starts_with('-')) {
+        match flag.as_str() {
+            "-L" => physical = false,
+            "-P" => physical = true,
+            "--" => {
+                rest = &rest[1..];
+                break;
+            },
+            flag => {
+                eprintln!("hsh: cd: {flag}: invalid option");
+                return 2;
+            }
+        }
+        rest = &rest[1..];
+    }
+    let (target,print) = match rest {
+        [] => match shell.variables.get("HOME") {
+            Some(home) => (home.to_string(),false),
+            None => {
+                eprintln!("hsh: cd: HOME not set");
+                return 1;
+            }
+        },
+        [dash] if dash == "-" => match shell.variables.get("OLDPWD") {
+            Some(previous) => (previous.to_string(),true),
+            None => {
+                eprintln!("hsh: cd: OLDPWD not set");
+                return 1;
+            }
+        },
+        [target] => (target.clone(),false),
+        _ => {
+            eprintln!("hsh: cd: too many arguments");
+            return 1;
+        }
+    };
+    let current = shell.variables.get("PWD").map(PathBuf::from).or_else(|| get_cwd_impl().ok()).unwrap_or_default();
+    let logical = normalize(&current.join(&target));
+    match change_working_dir_impl(if physical { Path::new(&target) } else { &logical }) {
+        Ok(()) => {},
+        Err(FsError::ChangeCwdError { errno }) => {
+            eprintln!("hsh: cd: {target}: {}",errno.desc());
+            return 1;
+        },
+        Err(err) => {
+            eprintln!("hsh: cd: {err}");
+            return 1;
+        }
+    }
+    let new = match physical {
+        true => get_cwd_impl().unwrap_or(logical),
+        false => logical
+    };
+    let updated = shell.variables.set("OLDPWD",&current.display().to_string())
+        .and_then(|()| shell.variables.set("PWD",&new.display().to_string()));
+    if let Err(err) = updated{
+        eprintln!("hsh: cd: {err}");
+        return 1;
+    }
+    if print && writeln!(std::io::stdout(),"{}",new.display()).is_err(){
+        return 1;
+    }
+    0
+}
+
+/// `path` without `.` components, and with each `..` taking away the component before it.
+fn normalize(path:&Path)->PathBuf{
+    let mut normalized = PathBuf::from("/");
+    for component in path.components(){
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            },
+            Component::Normal(name) => normalized.push(name),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
     }
+    normalized
 }
 
 pub fn load_startup_path()->String{
@@ -69,7 +238,7 @@ pub fn load_startup_path()->String{
             path.display().to_string()
         },
         Err(err)=>{
-            return err.to_string();
+            err.to_string()
         }
     }
 }
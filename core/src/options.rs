@@ -0,0 +1,122 @@
+//! Shell options and the `shopt` builtin that sets them.
+
+use std::io::Write;
+
+/// Options changing how the shell behaves, all off by default.
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct ShellOptions{
+    /// Patterns matching no files expand to nothing instead of themselves.
+    pub nullglob:bool,
+    /// Patterns matching no files are an error and the command is not run.
+    pub failglob:bool,
+    /// Wildcards also match names starting with `.`.
+    pub dotglob:bool,
+    /// `**` in a pathname matches any number of directories.
+    pub globstar:bool,
+    /// Patterns can use the `?(...)`, `*(...)`, `+(...)`, `@(...)` and `!(...)` groups.
+    pub extglob:bool
+}
+
+/// Names `shopt` knows, in the order it lists them.
+const OPTION_NAMES:&[&str] = &["dotglob","extglob","failglob","globstar","nullglob"];
+
+impl ShellOptions{
+    fn option_mut(&mut self,name:&str)->Option<&mut bool>{
+        Some(match name {
+            "nullglob" => &mut self.nullglob,
+            "failglob" => &mut self.failglob,
+            "dotglob" => &mut self.dotglob,
+            "globstar" => &mut self.globstar,
+            "extglob" => &mut self.extglob,
+            _ => return None
+        })
+    }
+}
+
+/// `shopt [-pqsu] [name...]`: `-s` turns the named options on and `-u` off. Otherwise
+/// lists them, succeeding only when all the named ones are on; `-q` lists nothing and
+/// `-p` lists them as commands.
+pub fn shopt_builtin(options:&mut ShellOptions,args:&[String])->i32{
+    let (mut set,mut unset,mut print,mut quiet) = (false,false,false,false);
+    let mut rest = args;
+    while let Some(letters) = rest.first().and_then(|arg| arg.strip_prefix('-')).filter(|letters| !letters.is_empty()) {
+        rest = &rest[1..];
+        if letters == "-"{
+            break;
+        }
+        for letter in letters.chars(){
+            match letter {
+                's' => set = true,
+                'u' => unset = true,
+                'p' => print = true,
+                'q' => quiet = true,
+                _ => {
+                    eprintln!("hsh: shopt: -{letter}: invalid option");
+                    return 2;
+                }
+            }
+        }
+    }
+    if set && unset{
+        eprintln!("hsh: shopt: cannot set and unset shell options simultaneously");
+        return 1;
+    }
+    let names:Vec<&str> = match rest {
+        [] => OPTION_NAMES.to_vec(),
+        names => names.iter().map(String::as_str).collect()
+    };
+    let mut status = 0;
+    let mut stdout = std::io::stdout();
+    for name in names{
+        let Some(enabled) = options.option_mut(name) else {
+            eprintln!("hsh: shopt: {name}: invalid shell option name");
+            status = 1;
+            continue;
+        };
+        if set || unset{
+            // without names, `-s` and `-u` list the options that are on or off
+            if !rest.is_empty(){
+                *enabled = set;
+                continue;
+            }
+            if *enabled != set{
+                continue;
+            }
+        }
+        if !*enabled && !rest.is_empty(){
+            status = 1;
+        }
+        let line = match print {
+            true => format!("shopt -{} {name}",if *enabled { 's' } else { 'u' }),
+            false => format!("{name:<15}\t{}",if *enabled { "on" } else { "off" })
+        };
+        if !quiet && writeln!(stdout,"{line}").is_err(){
+            return 1;
+        }
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn args(args:&[&str])->Vec<String>{
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_set_query_and_unset_options() {
+        let mut options = ShellOptions::default();
+        assert_eq!(shopt_builtin(&mut options,&args(&["-s","nullglob","extglob"])),0);
+        assert!(options.nullglob && options.extglob && !options.dotglob);
+        assert_eq!(shopt_builtin(&mut options,&args(&["-q","nullglob"])),0);
+        assert_eq!(shopt_builtin(&mut options,&args(&["-q","nullglob","dotglob"])),1);
+        assert_eq!(shopt_builtin(&mut options,&args(&["-u","nullglob"])),0);
+        assert!(!options.nullglob);
+        assert_eq!(shopt_builtin(&mut options,&args(&["-s"])),0);
+        assert!(options.extglob && !options.dotglob);
+        assert_eq!(shopt_builtin(&mut options,&args(&["-s","nosuchoption"])),1);
+        assert_eq!(shopt_builtin(&mut options,&args(&["-x"])),2);
+    }
+}
@@ -1,58 +1,1162 @@
+//! Builds a typed AST from the token stream, following the POSIX shell grammar with the
+//! common bash additions (`[[ ]]`, `function`, `select`, `time`, `|&`, `;&` and `;;&`).
 
+use derive_more::{Display, Error, From};
+use nom::{IResult, Parser, branch::alt, bytes::complete::{tag, take_while}, character::complete::{digit1, one_of, satisfy}, combinator::{map, map_res, recognize}, sequence::pair};
 
-use nom::{IResult, bytes::complete::take_while1, error::Error};
+use crate::error::TokenizeError;
+use crate::tokenizer::{HereDocument, ReservedWord, ShellTokens, Span, Token, TokenizerOptions, Word, WordPart, tokenize_here_document, tokenize_input_intermediate, tokenize_with_options};
 
-/// AST node values
-#[derive(Debug)]
-pub enum ASTExpr<'a>{
-    /// Node used for when variables are defined. First arg is key and second is value.
-    DefineVar(&'a str,&'a str),
+/// A sequence of and-or lists separated by `;`, `&` or newlines.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct CommandList{
+    pub items:Vec<ListItem>,
+    pub span:Span
+}
+
+/// One and-or list of a command list, and whether it ended with `&`.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct ListItem{
+    pub and_or:AndOrList,
+    pub asynchronous:bool,
+    pub span:Span
+}
+
+/// Pipelines joined by `&&` and `||`, evaluated left to right.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct AndOrList{
+    pub first:Pipeline,
+    pub rest:Vec<(AndOrOperator,Pipeline)>
+}
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum AndOrOperator{
+    And, // &&
+    Or // ||
+}
+
+/// Commands joined by `|`. `|&` is turned into `|` plus a `2>&1` on the command before it.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Pipeline{
+    /// Started with `!`, which inverts the exit status.
+    pub negated:bool,
+    /// Started with `time`.
+    pub timed:bool,
+    pub commands:Vec<Command>,
+    pub span:Span
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum Command{
+    Simple(SimpleCommand),
+    /// A compound command with the redirections written after it, e.g. `{ ls; } > out`.
+    Compound(CompoundCommand,Vec<Redirection>),
+    FunctionDefinition(FunctionDefinition)
+}
+
+/// Assignments and redirections around an optional command name and its arguments.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct SimpleCommand{
+    pub assignments:Vec<Assignment>,
+    /// Command name followed by its arguments. Empty for a command of only assignments.
+    pub words:Vec<ShellWord>,
+    pub redirections:Vec<Redirection>,
+    pub span:Span
+}
 
-    /// Node for subsistuting specific variable.
-    SubstituteVar(&'a str)
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct CompoundCommand{
+    pub kind:CompoundKind,
+    pub span:Span
 }
 
-#[derive(Debug)]
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum CompoundKind{
+    BraceGroup(CommandList), // { list; }
+    Subshell(CommandList), // ( list )
+    If{
+        /// `if` and each `elif` with their condition and `then` body.
+        branches:Vec<(CommandList,CommandList)>,
+        else_body:Option<CommandList>
+    },
+    While{ condition:CommandList, body:CommandList },
+    Until{ condition:CommandList, body:CommandList },
+    For{
+        variable:String,
+        /// Words after `in`. Without `in` the loop goes over the positional parameters.
+        words:Option<Vec<ShellWord>>,
+        body:CommandList
+    },
+    Select{ variable:String, words:Option<Vec<ShellWord>>, body:CommandList },
+    Case{ subject:ShellWord, items:Vec<CaseItem> },
+    /// `[[ ... ]]` with the words between the brackets, operators included as literals.
+    Conditional(Vec<ShellWord>)
+}
+
+/// `pattern | pattern) list ;;` inside a case command.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct CaseItem{
+    pub patterns:Vec<ShellWord>,
+    pub body:Option<CommandList>,
+    pub terminator:CaseTerminator,
+    pub span:Span
+}
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum CaseTerminator{
+    Break, // ;; or the last item without a terminator
+    FallThrough, // ;& runs the next body without testing its patterns
+    Continue // ;;& goes on testing the patterns of the next items
+}
+
+/// `name() compound-command` or `function name compound-command`.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct FunctionDefinition{
+    pub name:String,
+    pub body:Box<Command>,
+    pub span:Span
+}
+
+/// `NAME=value` written before a command name.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Assignment{
+    pub name:String,
+    pub value:ShellWord,
+    pub span:Span
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Redirection{
+    /// The fd written before the operator, when one was given.
+    pub fd:Option<u32>,
+    pub kind:RedirectionKind,
+    pub target:RedirectionTarget,
+    pub span:Span
+}
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum RedirectionKind{
+    Output, // >
+    Append, // >>
+    Clobber, // >|
+    DuplicateOutput, // >&
+    Input, // <
+    ReadWrite, // <>
+    DuplicateInput, // <&
+    HereDocument, // << and <<-
+    HereString, // <<<
+    OutputAndError, // &>
+    AppendOutputAndError // &>>
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum RedirectionTarget{
+    Word(ShellWord),
+    /// The body of a here-document, expanded like a double-quoted word unless its
+    /// delimiter was quoted.
+    HereDocument(ShellWord)
+}
+
+/// A word of the AST, with substitutions parsed into their own nodes.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct ShellWord{
+    pub segments:Vec<ASTExpr>,
+    pub span:Span
+}
+
+/// AST node values making up a word
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum ASTExpr{
+    Literal(String),
+    SingleQuoted(String),
+    DoubleQuoted(Vec<ASTExpr>),
+    Escaped(char),
+    /// Node for substituting a parameter, from `$name` or `${...}`.
+    SubstituteVar(ParameterExpansion),
+    CommandSubstitution(Box<CommandList>),
+    /// `$((...))`, its expression parsed like the inside of double quotes so parameters
+    /// and substitutions in it are expanded before it is evaluated.
+    Arithmetic(Vec<ASTExpr>),
+    GlobChar(char),
+    Tilde
+}
+
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct ParameterExpansion{
+    pub parameter:Parameter,
+    /// What `${...}` does with the value, None for a plain substitution.
+    pub operation:Option<ParameterOperation>
+}
+
+/// Operators of `${...}`. `check_null` is set for the forms with a colon, which treat an
+/// empty value like an unset one. Operand words are expanded only when they are used.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum ParameterOperation{
+    Length, // ${#name}
+    Default{ check_null:bool, word:Vec<ASTExpr> }, // ${name:-word}
+    Assign{ check_null:bool, word:Vec<ASTExpr> }, // ${name:=word}
+    Error{ check_null:bool, word:Vec<ASTExpr> }, // ${name:?word}
+    Alternative{ check_null:bool, word:Vec<ASTExpr> }, // ${name:+word}
+    RemovePrefix{ longest:bool, pattern:Vec<ASTExpr> }, // ${name#pattern} and ${name##pattern}
+    RemoveSuffix{ longest:bool, pattern:Vec<ASTExpr> }, // ${name%pattern} and ${name%%pattern}
+    /// `${name:offset}` and `${name:offset:length}`, both arithmetic expressions.
+    Substring{ offset:Vec<ASTExpr>, length:Option<Vec<ASTExpr>> },
+    /// `${name/pattern/replacement}`, `//` for every match, `/#` and `/%` anchored.
+    Replace{ mode:ReplaceMode, pattern:Vec<ASTExpr>, replacement:Vec<ASTExpr> },
+    /// `${name^pattern}` and `${name,pattern}` change the first character, `^^` and `,,`
+    /// every one. Without a pattern every character is changed.
+    ChangeCase{ upper:bool, all:bool, pattern:Vec<ASTExpr> }
+}
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum ReplaceMode{
+    First, // /
+    All, // //
+    Prefix, // /#
+    Suffix // /%
+}
+
+#[derive(Debug,Display,PartialEq,Eq,Clone)]
+pub enum Parameter{
+    #[display("{_0}")]
+    Named(String),
+    #[display("{_0}")]
+    Positional(usize),
+    #[display("{_0}")]
+    Special(char)
+}
+
+#[derive(Debug,Display,Error,From,PartialEq,Eq)]
 pub enum ParserError{
-    UnexpectedInput
+    #[display("syntax error near unexpected token `{found}`")]
+    #[from(ignore)]
+    UnexpectedToken{
+        #[error(not(source))]
+        found:String,
+        span:Span
+    },
+    #[display("syntax error: unexpected end of input, expected {expected}")]
+    #[from(ignore)]
+    UnexpectedEnd{
+        #[error(not(source))]
+        expected:String,
+        span:Span
+    },
+    #[display("bad substitution: {text}")]
+    #[from(ignore)]
+    BadSubstitution{
+        #[error(not(source))]
+        text:String,
+        span:Span
+    },
+    #[display("{_0}")]
+    Tokenize(TokenizeError)
 }
 
-// type ParserResult<'a,O> = Result<(O,&'a str),ParserError>;
+impl ParserError{
+    pub fn span(&self)->Span{
+        match self {
+            ParserError::UnexpectedToken { span, .. }
+            | ParserError::UnexpectedEnd { span, .. }
+            | ParserError::BadSubstitution { span, .. } => *span,
+            ParserError::Tokenize(err) => err.span()
+        }
+    }
 
-// pub trait Parser<O> {
-//     fn parse<'a>(&mut self,input:&'a str)->ParserResult<'a,O>;
+    /// Whether reading more input could complete the command.
+    pub fn is_incomplete(&self)->bool{
+        match self {
+            ParserError::UnexpectedEnd { .. } => true,
+            ParserError::Tokenize(err) => err.is_incomplete(),
+            _ => false
+        }
+    }
+}
 
-// }
+/// Tokenizes and parses a whole program.
+pub fn parse_program(source:&str)->Result<CommandList,ParserError>{
+    let tokens:Vec<Token> = tokenize_input_intermediate(source)?
+        .into_iter()
+        .filter(|token| !matches!(token.kind,ShellTokens::Whitespace | ShellTokens::Comment(_)))
+        .collect();
+    let end = Span { start: source.len(), end: source.len(), ..tokens.last().map(|token| token.span).unwrap_or_default() };
+    let mut parser = TokenParser { tokens: &tokens, position: 0, previous_end: 0, end };
+    let program = parser.parse_command_list()?;
+    match parser.peek() {
+        Some(token) => Err(parser.unexpected(token)),
+        None => Ok(program)
+    }
+}
 
+/// Recursive descent over the tokens, one method per grammar rule.
+struct TokenParser<'a>{
+    tokens:&'a [Token],
+    position:usize,
+    /// End offset of the last token taken, closing the span of the node being built.
+    previous_end:usize,
+    /// Where the input ends, for errors about missing tokens.
+    end:Span
+}
 
+impl<'a> TokenParser<'a>{
+    fn peek(&self)->Option<&'a Token>{
+        self.tokens.get(self.position)
+    }
 
+    fn peek_kind(&self)->Option<&'a ShellTokens>{
+        self.peek().map(|token| &token.kind)
+    }
 
-fn parse_dollar<'a>(cmd:&'a str)->IResult<&str,ASTExpr>{
-    // consume characters until char is alphanumeric and 
-    // if = encountered,end variable name and start consuming characters for variable value
-    // if whitespace encountered, take it as a variable 
+    fn advance(&mut self)->Option<&'a Token>{
+        let token = self.tokens.get(self.position)?;
+        self.position += 1;
+        self.previous_end = token.span.end;
+        Some(token)
+    }
+
+    fn next_if(&mut self,predicate:impl FnOnce(&ShellTokens)->bool)->Option<&'a Token>{
+        if predicate(self.peek_kind()?) { self.advance() } else { None }
+    }
+
+    fn next_is_reserved(&self,reserved:ReservedWord)->bool{
+        self.peek_kind() == Some(&ShellTokens::ReservedWord(reserved))
+    }
+
+    /// Span from the start of `start` to the end of the last token taken.
+    fn span_from(&self,start:Span)->Span{
+        Span { end: self.previous_end.max(start.start), ..start }
+    }
+
+    fn start_span(&self)->Span{
+        self.peek().map(|token| Span { end: token.span.start, ..token.span }).unwrap_or(self.end)
+    }
+
+    fn unexpected(&self,token:&Token)->ParserError{
+        ParserError::UnexpectedToken { found: token_text(&token.kind), span: token.span }
+    }
+
+    /// Error for when the next token is not `expected`.
+    fn expected(&self,expected:&str)->ParserError{
+        match self.peek() {
+            Some(token) => self.unexpected(token),
+            None => ParserError::UnexpectedEnd { expected: expected.to_string(), span: self.end }
+        }
+    }
+
+    fn expect_reserved(&mut self,reserved:ReservedWord)->Result<(),ParserError>{
+        if self.next_is_reserved(reserved){
+            self.advance();
+            Ok(())
+        }
+        else{
+            Err(self.expected(&format!("`{}`",reserved.as_str())))
+        }
+    }
+
+    fn expect(&mut self,kind:ShellTokens)->Result<(),ParserError>{
+        if self.peek_kind() == Some(&kind){
+            self.advance();
+            Ok(())
+        }
+        else{
+            Err(self.expected(&format!("`{}`",token_text(&kind))))
+        }
+    }
+
+    fn skip_newlines(&mut self){
+        while self.next_if(|kind| *kind == ShellTokens::Newline).is_some() {}
+    }
+
+    /// Parses and-or lists for as long as a command can start, so a list ends before
+    /// tokens like `then`, `done`, `)` or `;;` and the caller checks which one it wanted.
+    fn parse_command_list(&mut self)->Result<CommandList,ParserError>{
+        let start = self.start_span();
+        let mut items = vec![];
+        loop {
+            self.skip_newlines();
+            if !self.peek_kind().is_some_and(starts_command){
+                break;
+            }
+            let item_start = self.start_span();
+            let and_or = self.parse_and_or()?;
+            let mut asynchronous = false;
+            let separated = match self.peek_kind() {
+                Some(ShellTokens::Background) => {
+                    asynchronous = true;
+                    true
+                },
+                Some(ShellTokens::Semicolon) | Some(ShellTokens::Newline) => true,
+                _ => false
+            };
+            if separated{
+                self.advance();
+            }
+            items.push(ListItem { and_or, asynchronous, span: self.span_from(item_start) });
+            if !separated{
+                break;
+            }
+        }
+        Ok(CommandList { items, span: self.span_from(start) })
+    }
+
+    /// A command list that must hold at least one command, as the bodies of compound
+    /// commands do.
+    fn parse_compound_list(&mut self,expected:&str)->Result<CommandList,ParserError>{
+        let list = self.parse_command_list()?;
+        if list.items.is_empty(){
+            return Err(self.expected(expected));
+        }
+        Ok(list)
+    }
+
+    fn parse_and_or(&mut self)->Result<AndOrList,ParserError>{
+        let first = self.parse_pipeline()?;
+        let mut rest = vec![];
+        loop {
+            let operator = match self.peek_kind() {
+                Some(ShellTokens::And) => AndOrOperator::And,
+                Some(ShellTokens::Or) => AndOrOperator::Or,
+                _ => break
+            };
+            self.advance();
+            self.skip_newlines();
+            rest.push((operator,self.parse_pipeline()?));
+        }
+        Ok(AndOrList { first, rest })
+    }
+
+    fn parse_pipeline(&mut self)->Result<Pipeline,ParserError>{
+        let start = self.start_span();
+        let timed = self.next_if(|kind| *kind == ShellTokens::ReservedWord(ReservedWord::Time)).is_some();
+        let negated = self.next_if(|kind| *kind == ShellTokens::ReservedWord(ReservedWord::Exclamation)).is_some();
+        let mut commands = vec![self.parse_command()?];
+        while let Some(pipe) = self.next_if(|kind| matches!(kind,ShellTokens::Pipe | ShellTokens::PipeWithError)){
+            if pipe.kind == ShellTokens::PipeWithError
+                && let Some(command) = commands.last_mut(){
+                redirect_error_to_output(command,pipe.span);
+            }
+            self.skip_newlines();
+            commands.push(self.parse_command()?);
+        }
+        Ok(Pipeline { negated, timed, commands, span: self.span_from(start) })
+    }
+
+    fn parse_command(&mut self)->Result<Command,ParserError>{
+        let Some(token) = self.peek() else {
+            return Err(self.expected("a command"));
+        };
+        match &token.kind {
+            ShellTokens::ReservedWord(ReservedWord::Function) => self.parse_function_keyword(),
+            ShellTokens::ReservedWord(_) | ShellTokens::ParenthesesOpen => {
+                let compound = self.parse_compound_command()?;
+                let redirections = self.parse_redirections()?;
+                Ok(Command::Compound(compound,redirections))
+            },
+            ShellTokens::Word(word) if self.tokens.get(self.position + 1).is_some_and(|next| next.kind == ShellTokens::ParenthesesOpen) => {
+                let Some(name) = plain_literal(word) else {
+                    return Err(self.unexpected(&self.tokens[self.position + 1]));
+                };
+                self.advance();
+                self.expect(ShellTokens::ParenthesesOpen)?;
+                self.expect(ShellTokens::ParenthesesClose)?;
+                self.parse_function_body(name,token.span)
+            },
+            _ => self.parse_simple_command().map(Command::Simple)
+        }
+    }
+
+    /// `function name [()] body`
+    fn parse_function_keyword(&mut self)->Result<Command,ParserError>{
+        let start = self.start_span();
+        self.advance();
+        let name = match self.peek() {
+            Some(Token { kind: ShellTokens::Word(word), .. }) => plain_literal(word),
+            _ => None
+        };
+        let Some(name) = name else {
+            return Err(self.expected("a function name"));
+        };
+        self.advance();
+        if self.next_if(|kind| *kind == ShellTokens::ParenthesesOpen).is_some(){
+            self.expect(ShellTokens::ParenthesesClose)?;
+        }
+        self.parse_function_body(name,start)
+    }
+
+    fn parse_function_body(&mut self,name:String,start:Span)->Result<Command,ParserError>{
+        self.skip_newlines();
+        if !matches!(self.peek_kind(),Some(ShellTokens::ReservedWord(_)) | Some(ShellTokens::ParenthesesOpen)){
+            return Err(self.expected("a function body"));
+        }
+        let body = self.parse_compound_command()?;
+        let redirections = self.parse_redirections()?;
+        Ok(Command::FunctionDefinition(FunctionDefinition {
+            name,
+            body: Box::new(Command::Compound(body,redirections)),
+            span: self.span_from(start)
+        }))
+    }
+
+    fn parse_compound_command(&mut self)->Result<CompoundCommand,ParserError>{
+        let start = self.start_span();
+        let token = self.advance().expect("compound commands start with a token");
+        let kind = match &token.kind {
+            ShellTokens::ParenthesesOpen => {
+                let list = self.parse_compound_list("a command")?;
+                self.expect(ShellTokens::ParenthesesClose)?;
+                CompoundKind::Subshell(list)
+            },
+            ShellTokens::ReservedWord(ReservedWord::BracketOpen) => {
+                let list = self.parse_compound_list("a command")?;
+                self.expect_reserved(ReservedWord::BracketClose)?;
+                CompoundKind::BraceGroup(list)
+            },
+            ShellTokens::ReservedWord(ReservedWord::If) => self.parse_if()?,
+            ShellTokens::ReservedWord(ReservedWord::While) => {
+                let (condition,body) = self.parse_loop_parts()?;
+                CompoundKind::While { condition, body }
+            },
+            ShellTokens::ReservedWord(ReservedWord::Until) => {
+                let (condition,body) = self.parse_loop_parts()?;
+                CompoundKind::Until { condition, body }
+            },
+            ShellTokens::ReservedWord(ReservedWord::For) => {
+                let (variable,words,body) = self.parse_for_parts()?;
+                CompoundKind::For { variable, words, body }
+            },
+            ShellTokens::ReservedWord(ReservedWord::Select) => {
+                let (variable,words,body) = self.parse_for_parts()?;
+                CompoundKind::Select { variable, words, body }
+            },
+            ShellTokens::ReservedWord(ReservedWord::Case) => self.parse_case()?,
+            ShellTokens::ReservedWord(ReservedWord::DoubleBracketOpen) => self.parse_conditional()?,
+            _ => return Err(self.unexpected(token))
+        };
+        Ok(CompoundCommand { kind, span: self.span_from(start) })
+    }
+
+    /// `if list then list [elif list then list]... [else list] fi`
+    fn parse_if(&mut self)->Result<CompoundKind,ParserError>{
+        let mut branches = vec![];
+        let mut else_body = None;
+        loop {
+            let condition = self.parse_compound_list("a condition")?;
+            self.expect_reserved(ReservedWord::Then)?;
+            let body = self.parse_compound_list("a command")?;
+            branches.push((condition,body));
+            if self.next_if(|kind| *kind == ShellTokens::ReservedWord(ReservedWord::Elif)).is_some(){
+                continue;
+            }
+            if self.next_if(|kind| *kind == ShellTokens::ReservedWord(ReservedWord::Else)).is_some(){
+                else_body = Some(self.parse_compound_list("a command")?);
+            }
+            self.expect_reserved(ReservedWord::Fi)?;
+            return Ok(CompoundKind::If { branches, else_body });
+        }
+    }
 
-    fn is_alphanumeric(c:char)->bool{
-        (c.is_ascii_alphanumeric() || c== '_') && c!= '=' && !c.is_ascii_whitespace()
+    /// `list do list done` of while and until loops.
+    fn parse_loop_parts(&mut self)->Result<(CommandList,CommandList),ParserError>{
+        let condition = self.parse_compound_list("a condition")?;
+        let body = self.parse_do_group()?;
+        Ok((condition,body))
     }
 
-    let(rest,var_name) = take_while1(is_alphanumeric)(cmd)?;
-    match &rest.chars().nth(0){
-        Some('=') => {
-            // return key-value pair
-            let (remaining,var_value) = take_while1(|c:char|
-                !c.is_ascii_whitespace())(&rest[1..])?;
-            //  .map_err(|_| ParserError::UnexpectedInput)?;
-            return Ok((remaining,ASTExpr::DefineVar(var_name,var_value)))
+    fn parse_do_group(&mut self)->Result<CommandList,ParserError>{
+        self.expect_reserved(ReservedWord::Do)?;
+        let body = self.parse_compound_list("a command")?;
+        self.expect_reserved(ReservedWord::Done)?;
+        Ok(body)
+    }
+
+    /// `name [in word...;] do list done` of for and select loops.
+    fn parse_for_parts(&mut self)->Result<(String,Option<Vec<ShellWord>>,CommandList),ParserError>{
+        let variable = match self.peek() {
+            Some(Token { kind: ShellTokens::Word(word), .. }) => plain_literal(word).filter(|name| is_name(name)),
+            _ => None
+        };
+        let Some(variable) = variable else {
+            return Err(self.expected("a variable name"));
+        };
+        self.advance();
+        self.skip_newlines();
+        let mut words = None;
+        if self.next_if(|kind| *kind == ShellTokens::ReservedWord(ReservedWord::In)).is_some(){
+            let mut list = vec![];
+            while let Some(token) = self.next_if(|kind| matches!(kind,ShellTokens::Word(_))){
+                list.push(self.shell_word(token)?);
+            }
+            if self.next_if(|kind| matches!(kind,ShellTokens::Semicolon | ShellTokens::Newline)).is_none(){
+                return Err(self.expected("`;` or a newline"));
+            }
+            words = Some(list);
+        }
+        else{
+            self.next_if(|kind| *kind == ShellTokens::Semicolon);
+        }
+        self.skip_newlines();
+        let body = self.parse_do_group()?;
+        Ok((variable,words,body))
+    }
+
+    /// `case word in [(]pattern[|pattern]...) list ;; ... esac`
+    fn parse_case(&mut self)->Result<CompoundKind,ParserError>{
+        let subject = match self.next_if(|kind| matches!(kind,ShellTokens::Word(_))) {
+            Some(token) => self.shell_word(token)?,
+            None => return Err(self.expected("a word"))
+        };
+        self.skip_newlines();
+        self.expect_reserved(ReservedWord::In)?;
+        let mut items = vec![];
+        loop {
+            self.skip_newlines();
+            if self.next_if(|kind| *kind == ShellTokens::ReservedWord(ReservedWord::Esac)).is_some(){
+                break;
+            }
+            let start = self.start_span();
+            self.next_if(|kind| *kind == ShellTokens::ParenthesesOpen);
+            let mut patterns = vec![self.parse_case_pattern()?];
+            while self.next_if(|kind| *kind == ShellTokens::Pipe).is_some(){
+                patterns.push(self.parse_case_pattern()?);
+            }
+            self.expect(ShellTokens::ParenthesesClose)?;
+            let list = self.parse_command_list()?;
+            let body = if list.items.is_empty() { None } else { Some(list) };
+            let terminator = match self.peek_kind() {
+                Some(ShellTokens::DoubleSemicolon) => Some(CaseTerminator::Break),
+                Some(ShellTokens::SemicolonAnd) => Some(CaseTerminator::FallThrough),
+                Some(ShellTokens::DoubleSemicolonAnd) => Some(CaseTerminator::Continue),
+                _ => None
+            };
+            if terminator.is_some(){
+                self.advance();
+            }
+            items.push(CaseItem { patterns, body, terminator: terminator.unwrap_or(CaseTerminator::Break), span: self.span_from(start) });
+            if terminator.is_none(){
+                self.skip_newlines();
+                self.expect_reserved(ReservedWord::Esac)?;
+                break;
+            }
+        }
+        Ok(CompoundKind::Case { subject, items })
+    }
+
+    /// Patterns sit where commands could start, so reserved words there are plain patterns.
+    fn parse_case_pattern(&mut self)->Result<ShellWord,ParserError>{
+        match self.peek() {
+            Some(token @ Token { kind: ShellTokens::Word(_), .. }) => {
+                self.advance();
+                self.shell_word(token)
+            },
+            Some(Token { kind: ShellTokens::ReservedWord(reserved), span }) => {
+                self.advance();
+                Ok(ShellWord { segments: vec![ASTExpr::Literal(reserved.as_str().to_string())], span: *span })
+            },
+            _ => Err(self.expected("a pattern"))
+        }
+    }
+
+    /// `[[ word... ]]`, operator tokens inside become literal words for the test builtin.
+    fn parse_conditional(&mut self)->Result<CompoundKind,ParserError>{
+        let mut words = vec![];
+        loop {
+            let Some(token) = self.peek() else {
+                return Err(self.expected("`]]`"));
+            };
+            match &token.kind {
+                ShellTokens::ReservedWord(ReservedWord::DoubleBracketClose) => {
+                    self.advance();
+                    break;
+                },
+                ShellTokens::Word(_) => words.push(self.shell_word(token)?),
+                ShellTokens::Newline | ShellTokens::Semicolon | ShellTokens::Background
+                    | ShellTokens::HereDocument(_) => return Err(self.unexpected(token)),
+                kind => words.push(ShellWord { segments: vec![ASTExpr::Literal(token_text(kind))], span: token.span })
+            }
+            self.advance();
+        }
+        if words.is_empty(){
+            return Err(self.expected("an expression"));
+        }
+        Ok(CompoundKind::Conditional(words))
+    }
+
+    fn parse_simple_command(&mut self)->Result<SimpleCommand,ParserError>{
+        let start = self.start_span();
+        let mut assignments = vec![];
+        let mut words = vec![];
+        let mut redirections = vec![];
+        while let Some(token) = self.peek() {
+            match &token.kind {
+                ShellTokens::Word(word) => {
+                    self.advance();
+                    if words.is_empty()
+                        && let Some(assignment) = self.assignment(word,token.span)?{
+                        assignments.push(assignment);
+                        continue;
+                    }
+                    words.push(self.shell_word(token)?);
+                },
+                kind if redirection_kind(kind).is_some() => redirections.push(self.parse_redirection()?),
+                _ => break
+            }
+        }
+        if assignments.is_empty() && words.is_empty() && redirections.is_empty(){
+            return Err(self.expected("a command"));
+        }
+        Ok(SimpleCommand { assignments, words, redirections, span: self.span_from(start) })
+    }
+
+    fn parse_redirections(&mut self)->Result<Vec<Redirection>,ParserError>{
+        let mut redirections = vec![];
+        while self.peek_kind().is_some_and(|kind| redirection_kind(kind).is_some()){
+            redirections.push(self.parse_redirection()?);
+        }
+        Ok(redirections)
+    }
+
+    fn parse_redirection(&mut self)->Result<Redirection,ParserError>{
+        let operator = self.advance().expect("redirection operator was peeked");
+        let (fd,kind) = redirection_kind(&operator.kind).expect("redirection operator was peeked");
+        let target = match (kind,self.peek()) {
+            (RedirectionKind::HereDocument,Some(token @ Token { kind: ShellTokens::HereDocument(document), .. })) => {
+                self.advance();
+                RedirectionTarget::HereDocument(here_document_word(document,token.span)?)
+            },
+            (RedirectionKind::HereDocument,_) => return Err(self.expected("a here-document delimiter")),
+            (_,Some(token @ Token { kind: ShellTokens::Word(_), .. })) => {
+                self.advance();
+                RedirectionTarget::Word(self.shell_word(token)?)
+            },
+            _ => return Err(self.expected("a file name"))
+        };
+        Ok(Redirection { fd, kind, target, span: self.span_from(operator.span) })
+    }
+
+    /// Splits `NAME=value` into an assignment, or gives None for any other word.
+    fn assignment(&self,word:&Word,span:Span)->Result<Option<Assignment>,ParserError>{
+        let Some(WordPart::Literal(first)) = word.parts.first() else {
+            return Ok(None);
+        };
+        let Some((name,value)) = first.split_once('=') else {
+            return Ok(None);
+        };
+        if !is_name(name){
+            return Ok(None);
+        }
+        let mut parts = vec![];
+        if !value.is_empty(){
+            parts.push(WordPart::Literal(value.to_string()));
+        }
+        parts.extend(word.parts[1..].iter().cloned());
+        let value_span = Span { start: span.start + name.len() + 1, column: span.column + name.len() + 1, ..span };
+        Ok(Some(Assignment {
+            name: name.to_string(),
+            value: ShellWord { segments: convert_parts(&parts,value_span)?, span: value_span },
+            span
+        }))
+    }
+
+    fn shell_word(&self,token:&Token)->Result<ShellWord,ParserError>{
+        let ShellTokens::Word(word) = &token.kind else {
+            return Err(self.unexpected(token));
+        };
+        Ok(ShellWord { segments: convert_parts(&word.parts,token.span)?, span: token.span })
+    }
+}
+
+/// The body of a here-document as a word: taken literally after a quoted delimiter, and
+/// otherwise with its expansions parsed.
+fn here_document_word(document:&HereDocument,span:Span)->Result<ShellWord,ParserError>{
+    let segments = match document.quoted {
+        true => vec![ASTExpr::SingleQuoted(document.body.clone())],
+        false => convert_parts(&tokenize_here_document(&document.body)?,span)?
+    };
+    Ok(ShellWord { segments, span })
+}
+
+/// Turns the tokenizer's word parts into AST nodes, parsing what sits inside `${...}` and
+/// `$(...)`. Errors inside them point at the whole word.
+fn convert_parts(parts:&[WordPart],span:Span)->Result<Vec<ASTExpr>,ParserError>{
+    parts.iter().map(|part| Ok(match part {
+        WordPart::Literal(text) => ASTExpr::Literal(text.clone()),
+        WordPart::SingleQuoted(text) => ASTExpr::SingleQuoted(text.clone()),
+        WordPart::DoubleQuoted(inner) => ASTExpr::DoubleQuoted(convert_parts(inner,span)?),
+        WordPart::Variable(name) => substitute(Parameter::Named(name.clone())),
+        WordPart::Positional(position) => substitute(Parameter::Positional(*position)),
+        WordPart::SpecialParameter(char) => substitute(Parameter::Special(*char)),
+        WordPart::BracedParameter(text) => parse_braced_parameter(text,span)?,
+        WordPart::Arithmetic(expression) => ASTExpr::Arithmetic(parse_arithmetic(expression,span)?),
+        WordPart::CommandSubstitution(command) => match parse_program(command) {
+            Ok(list) => ASTExpr::CommandSubstitution(Box::new(list)),
+            Err(err) => return Err(ParserError::BadSubstitution { text: format!("$({command}): {err}"), span })
+        },
+        WordPart::Escaped(char) => ASTExpr::Escaped(*char),
+        WordPart::GlobChar(char) => ASTExpr::GlobChar(*char),
+        WordPart::Tilde => ASTExpr::Tilde,
+    })).collect()
+}
+
+/// Parses an arithmetic expression as if it were between double quotes. Text that cannot be
+/// read that way, e.g. with a stray `"`, stays one literal for the evaluator to reject.
+fn parse_arithmetic(expression:&str,span:Span)->Result<Vec<ASTExpr>,ParserError>{
+    let tokens = tokenize_input_intermediate(&format!("\"{expression}\"")).unwrap_or_default();
+    match tokens.as_slice() {
+        [Token { kind: ShellTokens::Word(word), .. }] => match word.parts.as_slice() {
+            [WordPart::DoubleQuoted(inner)] => convert_parts(inner,span),
+            _ => Ok(vec![ASTExpr::Literal(expression.to_string())])
         },
-        Some(' ') => {
-            return Ok((rest,ASTExpr::SubstituteVar(var_name)))
+        _ => Ok(vec![ASTExpr::Literal(expression.to_string())])
+    }
+}
+
+fn substitute(parameter:Parameter)->ASTExpr{
+    ASTExpr::SubstituteVar(ParameterExpansion { parameter, operation: None })
+}
+
+/// Parses the text between `${` and `}`.
+fn parse_braced_parameter(text:&str,span:Span)->Result<ASTExpr,ParserError>{
+    let bad_substitution = || ParserError::BadSubstitution { text: format!("${{{text}}}"), span };
+    // `${#}` is the special parameter, `${#name}` the length of name
+    if let Some(name) = text.strip_prefix('#').filter(|name| !name.is_empty()){
+        return match parse_dollar(name) {
+            Ok(("",parameter)) => Ok(ASTExpr::SubstituteVar(ParameterExpansion { parameter, operation: Some(ParameterOperation::Length) })),
+            _ => Err(bad_substitution())
+        };
+    }
+    let Ok((rest,parameter)) = parse_dollar(text) else {
+        return Err(bad_substitution());
+    };
+    if rest.is_empty(){
+        return Ok(substitute(parameter));
+    }
+    let Ok((operand,operator)) = parse_parameter_operator(rest) else {
+        return Err(bad_substitution());
+    };
+    let word = |text:&str| parse_operand(text,span);
+    let check_null = operator.starts_with(':');
+    let operation = match operator {
+        ":-" | "-" => ParameterOperation::Default { check_null, word: word(operand)? },
+        ":=" | "=" => ParameterOperation::Assign { check_null, word: word(operand)? },
+        ":?" | "?" => ParameterOperation::Error { check_null, word: word(operand)? },
+        ":+" | "+" => ParameterOperation::Alternative { check_null, word: word(operand)? },
+        "#" | "##" => ParameterOperation::RemovePrefix { longest: operator == "##", pattern: word(operand)? },
+        "%" | "%%" => ParameterOperation::RemoveSuffix { longest: operator == "%%", pattern: word(operand)? },
+        ":" => {
+            let (offset,length) = match operand.split_once(':') {
+                Some((offset,length)) => (offset,Some(length)),
+                None => (operand,None)
+            };
+            ParameterOperation::Substring {
+                offset: parse_arithmetic(offset,span)?,
+                length: length.map(|length| parse_arithmetic(length,span)).transpose()?
+            }
+        },
+        "/" | "//" | "/#" | "/%" => {
+            let mode = match operator {
+                "//" => ReplaceMode::All,
+                "/#" => ReplaceMode::Prefix,
+                "/%" => ReplaceMode::Suffix,
+                _ => ReplaceMode::First
+            };
+            let (pattern,replacement) = split_replacement(operand);
+            ParameterOperation::Replace { mode, pattern: word(pattern)?, replacement: word(replacement)? }
+        },
+        "^" | "^^" | "," | ",," => ParameterOperation::ChangeCase {
+            upper: operator.starts_with('^'),
+            all: operator.len() == 2,
+            pattern: word(operand)?
+        },
+        _ => return Err(bad_substitution())
+    };
+    Ok(ASTExpr::SubstituteVar(ParameterExpansion { parameter, operation: Some(operation) }))
+}
+
+/// The operator following the parameter inside `${...}`, longest first.
+fn parse_parameter_operator(text:&str)->IResult<&str,&str>{
+    alt((
+        alt((tag(":-"),tag(":="),tag(":?"),tag(":+"),tag(":"))),
+        alt((tag("-"),tag("="),tag("?"),tag("+"))),
+        alt((tag("##"),tag("#"),tag("%%"),tag("%"))),
+        alt((tag("//"),tag("/#"),tag("/%"),tag("/"))),
+        alt((tag("^^"),tag("^"),tag(",,"),tag(","))),
+    )).parse(text)
+}
+
+/// Splits `pattern/replacement` at the first `/` that is neither escaped nor quoted.
+fn split_replacement(operand:&str)->(&str,&str){
+    let mut quote = None;
+    let mut escaped = false;
+    for (index,char) in operand.char_indices(){
+        match (char,quote) {
+            _ if escaped => escaped = false,
+            ('\\',Some('\'')) => {},
+            ('\\',_) => escaped = true,
+            ('\'' | '"',None) => quote = Some(char),
+            (char,Some(open)) if char == open => quote = None,
+            ('/',None) => return (&operand[..index],&operand[index + 1..]),
+            _ => {}
+        }
+    }
+    (operand,"")
+}
+
+/// Parses an operand of `${...}` as a word, keeping blanks and operator characters in it
+/// as literal text since the braces already delimit it.
+fn parse_operand(text:&str,span:Span)->Result<Vec<ASTExpr>,ParserError>{
+    let options = TokenizerOptions { keep_comments: true };
+    let Ok(tokens) = tokenize_with_options(text,&options) else {
+        return Ok(vec![ASTExpr::Literal(text.to_string())]);
+    };
+    let mut segments = vec![];
+    for token in tokens{
+        match token.kind {
+            ShellTokens::Word(word) => segments.extend(convert_parts(&word.parts,span)?),
+            _ => segments.push(ASTExpr::Literal(text[token.span.start..token.span.end].to_string()))
+        }
+    }
+    Ok(segments)
+}
+
+/// Parses the parameter a `$` expansion refers to: a name, a position or a special parameter.
+fn parse_dollar(cmd:&str)->IResult<&str,Parameter>{
+    fn is_name_char(c:char)->bool{
+        c.is_ascii_alphanumeric() || c == '_'
+    }
+
+    alt((
+        map(recognize(pair(satisfy(|c| c.is_ascii_alphabetic() || c == '_'),take_while(is_name_char))),
+            |name:&str| Parameter::Named(name.to_string())),
+        map_res(digit1,|digits:&str| digits.parse::<usize>().map(|position| {
+            if position == 0 { Parameter::Special('0') } else { Parameter::Positional(position) }
+        })),
+        map(one_of("?#@*$!-"),Parameter::Special),
+    )).parse(cmd)
+}
+
+/// Whether `name` can be a variable name: letters, digits and `_`, not starting with a digit.
+pub(crate) fn is_name(name:&str)->bool{
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The text of a word made of a single unquoted literal, as function and loop names must be.
+fn plain_literal(word:&Word)->Option<String>{
+    match word.parts.as_slice() {
+        [WordPart::Literal(text)] => Some(text.clone()),
+        _ => None
+    }
+}
+
+fn starts_command(kind:&ShellTokens)->bool{
+    match kind {
+        ShellTokens::Word(_) | ShellTokens::ParenthesesOpen => true,
+        ShellTokens::ReservedWord(reserved) => matches!(reserved,
+            ReservedWord::If | ReservedWord::While | ReservedWord::Until | ReservedWord::For
+            | ReservedWord::Case | ReservedWord::BracketOpen | ReservedWord::Exclamation
+            | ReservedWord::DoubleBracketOpen | ReservedWord::Function | ReservedWord::Select
+            | ReservedWord::Time),
+        kind => redirection_kind(kind).is_some()
+    }
+}
+
+fn redirection_kind(kind:&ShellTokens)->Option<(Option<u32>,RedirectionKind)>{
+    Some(match kind {
+        ShellTokens::RedirectOutput(fd) => (*fd,RedirectionKind::Output),
+        ShellTokens::AppendOutput(fd) => (*fd,RedirectionKind::Append),
+        ShellTokens::Clobber(fd) => (*fd,RedirectionKind::Clobber),
+        ShellTokens::DuplicateOutput(fd) => (*fd,RedirectionKind::DuplicateOutput),
+        ShellTokens::RedirectInput(fd) => (*fd,RedirectionKind::Input),
+        ShellTokens::ReadWrite(fd) => (*fd,RedirectionKind::ReadWrite),
+        ShellTokens::DuplicateInput(fd) => (*fd,RedirectionKind::DuplicateInput),
+        ShellTokens::HereDoc(fd) | ShellTokens::HereDocStripTabs(fd) => (*fd,RedirectionKind::HereDocument),
+        ShellTokens::HereString(fd) => (*fd,RedirectionKind::HereString),
+        ShellTokens::RedirectOutputAndError => (None,RedirectionKind::OutputAndError),
+        ShellTokens::AppendOutputAndError => (None,RedirectionKind::AppendOutputAndError),
+        _ => return None
+    })
+}
+
+/// Adds the `2>&1` that `|&` stands for, after any redirections already on the command.
+fn redirect_error_to_output(command:&mut Command,span:Span){
+    let redirection = Redirection {
+        fd: Some(2),
+        kind: RedirectionKind::DuplicateOutput,
+        target: RedirectionTarget::Word(ShellWord { segments: vec![ASTExpr::Literal(String::from("1"))], span }),
+        span
+    };
+    match command {
+        Command::Simple(simple) => simple.redirections.push(redirection),
+        Command::Compound(_,redirections) => redirections.push(redirection),
+        Command::FunctionDefinition(_) => {}
+    }
+}
+
+/// How a token is written in the source, for error messages.
+fn token_text(kind:&ShellTokens)->String{
+    let text = match kind {
+        ShellTokens::Word(word) => return word.parts.iter().map(WordPart::source_text).collect(),
+        ShellTokens::ReservedWord(reserved) => reserved.as_str(),
+        ShellTokens::Comment(comment) => return format!("#{comment}"),
+        ShellTokens::HereDocument(_) => "here-document",
+        ShellTokens::Pipe => "|",
+        ShellTokens::PipeWithError => "|&",
+        ShellTokens::And => "&&",
+        ShellTokens::Or => "||",
+        ShellTokens::Semicolon => ";",
+        ShellTokens::DoubleSemicolon => ";;",
+        ShellTokens::SemicolonAnd => ";&",
+        ShellTokens::DoubleSemicolonAnd => ";;&",
+        ShellTokens::Background => "&",
+        ShellTokens::RedirectOutput(_) => ">",
+        ShellTokens::AppendOutput(_) => ">>",
+        ShellTokens::Clobber(_) => ">|",
+        ShellTokens::DuplicateOutput(_) => ">&",
+        ShellTokens::RedirectInput(_) => "<",
+        ShellTokens::ReadWrite(_) => "<>",
+        ShellTokens::DuplicateInput(_) => "<&",
+        ShellTokens::HereDoc(_) => "<<",
+        ShellTokens::HereDocStripTabs(_) => "<<-",
+        ShellTokens::HereString(_) => "<<<",
+        ShellTokens::RedirectOutputAndError => "&>",
+        ShellTokens::AppendOutputAndError => "&>>",
+        ShellTokens::ParenthesesOpen => "(",
+        ShellTokens::ParenthesesClose => ")",
+        ShellTokens::Whitespace => " ",
+        ShellTokens::Newline => "newline",
+    };
+    text.to_string()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn literal(text:&str)->Vec<ASTExpr>{
+        vec![ASTExpr::Literal(text.to_string())]
+    }
+
+    fn first_command(source:&str)->Command{
+        let list = parse_program(source).unwrap();
+        list.items[0].and_or.first.commands[0].clone()
+    }
+
+    fn simple(source:&str)->SimpleCommand{
+        match first_command(source) {
+            Command::Simple(simple) => simple,
+            other => panic!("expected a simple command, got {other:?}")
         }
-        _ =>{
-            eprintln!("Invalid Input.");
-            // return Err();
-            return Err(nom::Err::Error(Error::new(cmd, nom::error::ErrorKind::Char)));
-            
+    }
+
+    fn compound(source:&str)->CompoundKind{
+        match first_command(source) {
+            Command::Compound(compound,_) => compound.kind,
+            other => panic!("expected a compound command, got {other:?}")
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_simple_command_parts() {
+        let command = simple("A=1 B=x$y echo hi 2>err C=3");
+        assert_eq!(command.assignments.len(),2);
+        assert_eq!(command.assignments[0].name,"A");
+        assert_eq!(command.assignments[0].value.segments,literal("1"));
+        assert_eq!(
+            command.assignments[1].value.segments,
+            vec![ASTExpr::Literal(String::from("x")),substitute(Parameter::Named(String::from("y")))]
+        );
+        let words:Vec<_> = command.words.iter().map(|word| word.segments.clone()).collect();
+        assert_eq!(words,vec![literal("echo"),literal("hi"),literal("C=3")]);
+        assert_eq!(command.redirections.len(),1);
+        assert_eq!(command.redirections[0].fd,Some(2));
+        assert_eq!(command.redirections[0].kind,RedirectionKind::Output);
+        assert_eq!(command.span,Span { start: 0, end: 27, line: 1, column: 1 });
+    }
+
+    #[test]
+    fn test_lists_pipelines_and_and_or() {
+        let list = parse_program("! a | b |& c && d || e & f; g\nh").unwrap();
+        assert_eq!(list.items.len(),4);
+        assert!(list.items[0].asynchronous);
+        assert!(!list.items[1].asynchronous);
+        let and_or = &list.items[0].and_or;
+        assert!(and_or.first.negated);
+        assert_eq!(and_or.first.commands.len(),3);
+        let Command::Simple(b) = &and_or.first.commands[1] else { panic!() };
+        assert_eq!(b.redirections[0].fd,Some(2));
+        assert_eq!(b.redirections[0].kind,RedirectionKind::DuplicateOutput);
+        let operators:Vec<_> = and_or.rest.iter().map(|(operator,_)| *operator).collect();
+        assert_eq!(operators,vec![AndOrOperator::And,AndOrOperator::Or]);
+    }
+
+    #[test]
+    fn test_compound_commands() {
+        let CompoundKind::If { branches, else_body } = compound("if a; then b; elif c\nthen d; else e; fi") else { panic!() };
+        assert_eq!(branches.len(),2);
+        assert!(else_body.is_some());
+        assert!(matches!(compound("while a; do b; done"),CompoundKind::While { .. }));
+        assert!(matches!(compound("until a; do b; done"),CompoundKind::Until { .. }));
+        assert!(matches!(compound("{ a; b; }"),CompoundKind::BraceGroup(list) if list.items.len() == 2));
+        assert!(matches!(compound("(a; b)"),CompoundKind::Subshell(list) if list.items.len() == 2));
+        let CompoundKind::For { variable, words, .. } = compound("for i in 1 2 3; do echo $i; done") else { panic!() };
+        assert_eq!(variable,"i");
+        assert_eq!(words.unwrap().len(),3);
+        let CompoundKind::For { words, .. } = compound("for i\ndo echo $i; done") else { panic!() };
+        assert_eq!(words,None);
+        let CompoundKind::Conditional(words) = compound("[[ -n $a && $b == x ]]") else { panic!() };
+        assert_eq!(words.len(),6);
+    }
+
+    #[test]
+    fn test_case_items() {
+        let CompoundKind::Case { items, .. } = compound("case $x in\n(a|b) one;;\nc) two;&\nd) ;;&\n*) last\nesac") else { panic!() };
+        let terminators:Vec<_> = items.iter().map(|item| item.terminator).collect();
+        assert_eq!(terminators,vec![CaseTerminator::Break,CaseTerminator::FallThrough,CaseTerminator::Continue,CaseTerminator::Break]);
+        assert_eq!(items[0].patterns.len(),2);
+        assert_eq!(items[2].body,None);
+        assert_eq!(items[3].patterns[0].segments,vec![ASTExpr::GlobChar('*')]);
+    }
+
+    #[test]
+    fn test_function_definitions_and_redirections() {
+        let Command::FunctionDefinition(function) = first_command("greet() { echo hi; } > out") else { panic!() };
+        assert_eq!(function.name,"greet");
+        let Command::Compound(_,redirections) = *function.body else { panic!() };
+        assert_eq!(redirections.len(),1);
+        let Command::FunctionDefinition(function) = first_command("function greet\n{ echo hi; }") else { panic!() };
+        assert_eq!(function.name,"greet");
+        let command = simple("cat <<EOF\nhello $name\nEOF\n");
+        let RedirectionTarget::HereDocument(body) = &command.redirections[0].target else { panic!() };
+        assert_eq!(body.segments,vec![
+            ASTExpr::Literal(String::from("hello ")),
+            substitute(Parameter::Named(String::from("name"))),
+            ASTExpr::Literal(String::from("\n"))
+        ]);
+        let command = simple("cat <<'EOF'\nhello $name\nEOF\n");
+        let RedirectionTarget::HereDocument(body) = &command.redirections[0].target else { panic!() };
+        assert_eq!(body.segments,vec![ASTExpr::SingleQuoted(String::from("hello $name\n"))]);
+    }
+
+    #[test]
+    fn test_substitutions_are_parsed() {
+        let command = simple("echo $(ls | wc) ${HOME} $((1+2))");
+        let ASTExpr::CommandSubstitution(inner) = &command.words[1].segments[0] else { panic!() };
+        assert_eq!(inner.items[0].and_or.first.commands.len(),2);
+        assert_eq!(command.words[2].segments,vec![substitute(Parameter::Named(String::from("HOME")))]);
+        assert_eq!(command.words[3].segments,vec![ASTExpr::Arithmetic(literal("1+2"))]);
+        assert!(matches!(parse_program("echo ${a@}"),Err(ParserError::BadSubstitution { .. })));
+    }
+
+    #[test]
+    fn test_errors_carry_spans() {
+        let err = parse_program("echo a; fi").unwrap_err();
+        assert_eq!(err,ParserError::UnexpectedToken { found: String::from("fi"), span: Span { start: 8, end: 10, line: 1, column: 9 } });
+        assert!(!err.is_incomplete());
+        assert!(parse_program("if true; then").unwrap_err().is_incomplete());
+        assert!(parse_program("echo a |").unwrap_err().is_incomplete());
+        assert!(parse_program("echo 'a").unwrap_err().is_incomplete());
+        assert!(matches!(parse_program("; ls"),Err(ParserError::UnexpectedToken { .. })));
+        assert!(matches!(parse_program("{ }"),Err(ParserError::UnexpectedToken { .. })));
+    }
+}
@@ -0,0 +1,348 @@
+//! Shell pattern matching, as used by pathname expansion and `${name#pattern}`. Patterns
+//! are strings in which `*`, `?` and `[...]` are special, along with the `@(a|b)` style
+//! groups of extglob, and a backslash makes the next character literal, so expansion
+//! escapes whatever came from quotes.
+
+/// A parsed pattern.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Pattern{
+    tokens:Vec<PatternToken>
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum PatternToken{
+    Literal(char),
+    AnyChar, // ?
+    AnyString, // *
+    Bracket(BracketExpression), // [...]
+    /// An extglob group with its `|`-separated alternatives.
+    Group(GroupKind,Vec<Vec<PatternToken>>)
+}
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum GroupKind{
+    ZeroOrOne, // ?(...)
+    ZeroOrMore, // *(...)
+    OneOrMore, // +(...)
+    One, // @(...)
+    Not // !(...)
+}
+
+impl GroupKind{
+    fn from_char(char:char)->Option<Self>{
+        Some(match char {
+            '?' => GroupKind::ZeroOrOne,
+            '*' => GroupKind::ZeroOrMore,
+            '+' => GroupKind::OneOrMore,
+            '@' => GroupKind::One,
+            '!' => GroupKind::Not,
+            _ => return None
+        })
+    }
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+struct BracketExpression{
+    /// Started with `!` or `^`.
+    negated:bool,
+    items:Vec<BracketItem>
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+enum BracketItem{
+    Char(char),
+    Range(char,char), // a-z
+    Class(CharClass) // [:alpha:]
+}
+
+/// The POSIX character classes usable inside brackets.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum CharClass{
+    Alnum,
+    Alpha,
+    Blank,
+    Cntrl,
+    Digit,
+    Graph,
+    Lower,
+    Print,
+    Punct,
+    Space,
+    Upper,
+    Xdigit
+}
+
+impl CharClass{
+    fn from_name(name:&str)->Option<Self>{
+        Some(match name {
+            "alnum" => CharClass::Alnum,
+            "alpha" => CharClass::Alpha,
+            "blank" => CharClass::Blank,
+            "cntrl" => CharClass::Cntrl,
+            "digit" => CharClass::Digit,
+            "graph" => CharClass::Graph,
+            "lower" => CharClass::Lower,
+            "print" => CharClass::Print,
+            "punct" => CharClass::Punct,
+            "space" => CharClass::Space,
+            "upper" => CharClass::Upper,
+            "xdigit" => CharClass::Xdigit,
+            _ => return None
+        })
+    }
+
+    fn contains(&self,char:char)->bool{
+        match self {
+            CharClass::Alnum => char.is_alphanumeric(),
+            CharClass::Alpha => char.is_alphabetic(),
+            CharClass::Blank => char == ' ' || char == '\t',
+            CharClass::Cntrl => char.is_control(),
+            CharClass::Digit => char.is_ascii_digit(),
+            CharClass::Graph => !char.is_control() && !char.is_whitespace(),
+            CharClass::Lower => char.is_lowercase(),
+            CharClass::Print => !char.is_control(),
+            CharClass::Punct => char.is_ascii_punctuation(),
+            CharClass::Space => char.is_whitespace(),
+            CharClass::Upper => char.is_uppercase(),
+            CharClass::Xdigit => char.is_ascii_hexdigit()
+        }
+    }
+}
+
+impl BracketExpression{
+    fn matches(&self,char:char)->bool{
+        let found = self.items.iter().any(|item| match item {
+            BracketItem::Char(expected) => *expected == char,
+            BracketItem::Range(first,last) => (*first..=*last).contains(&char),
+            BracketItem::Class(class) => class.contains(char)
+        });
+        found != self.negated
+    }
+}
+
+impl Pattern{
+    /// Parses `pattern`, recognizing extglob groups when `extended` is set.
+    pub fn parse(pattern:&str,extended:bool)->Self{
+        let chars:Vec<char> = pattern.chars().collect();
+        // outside a group `|` and `)` are ordinary characters
+        Pattern { tokens: parse_tokens(&chars,&mut 0,extended,false) }
+    }
+
+    /// Whether the whole of `text` matches.
+    pub fn matches(&self,text:&str)->bool{
+        let chars:Vec<char> = text.chars().collect();
+        match_tokens(&self.tokens,&chars)
+    }
+
+    /// Whether the pattern matches only the empty string, having nothing in it.
+    pub fn is_empty(&self)->bool{
+        self.tokens.is_empty()
+    }
+
+    /// Whether the pattern has anything but literal characters.
+    pub fn has_wildcards(&self)->bool{
+        self.tokens.iter().any(|token| !matches!(token,PatternToken::Literal(_)))
+    }
+
+    /// Whether the pattern starts with a literal `.`, which is what lets it match the names
+    /// of hidden files.
+    pub fn starts_with_dot(&self)->bool{
+        self.tokens.first() == Some(&PatternToken::Literal('.'))
+    }
+}
+
+/// Parses from `index` up to the end of `chars`, or inside a group up to the `|` or `)`
+/// that ends the alternative, leaving `index` on it.
+fn parse_tokens(chars:&[char],index:&mut usize,extended:bool,in_group:bool)->Vec<PatternToken>{
+    let mut tokens = vec![];
+    while *index < chars.len(){
+        let token = match chars[*index] {
+            '|' | ')' if in_group => break,
+            '\\' if *index + 1 < chars.len() => {
+                *index += 1;
+                PatternToken::Literal(chars[*index])
+            },
+            char if extended && chars.get(*index + 1) == Some(&'(') && let Some(kind) = GroupKind::from_char(char) => {
+                match parse_group(chars,*index + 2,extended) {
+                    Some((alternatives,end)) => {
+                        *index = end;
+                        tokens.push(PatternToken::Group(kind,alternatives));
+                        continue;
+                    },
+                    // without a closing `)` the group is ordinary characters
+                    None => PatternToken::Literal(char)
+                }
+            },
+            '*' => PatternToken::AnyString,
+            '?' => PatternToken::AnyChar,
+            '[' => match parse_bracket(&chars[*index + 1..]) {
+                Some((bracket,used)) => {
+                    *index += used;
+                    PatternToken::Bracket(bracket)
+                },
+                // without a closing `]` the `[` is an ordinary character
+                None => PatternToken::Literal('[')
+            },
+            char => PatternToken::Literal(char)
+        };
+        tokens.push(token);
+        *index += 1;
+    }
+    tokens
+}
+
+/// Parses the alternatives of a group starting at `start`, just after its `(`. Gives them
+/// along with the index following the closing `)`.
+fn parse_group(chars:&[char],start:usize,extended:bool)->Option<(Vec<Vec<PatternToken>>,usize)>{
+    let mut index = start;
+    let mut alternatives = vec![];
+    loop {
+        alternatives.push(parse_tokens(chars,&mut index,extended,true));
+        match chars.get(index)? {
+            '|' => index += 1,
+            _ => return Some((alternatives,index + 1))
+        }
+    }
+}
+
+/// Parses what follows a `[` up to its `]`, giving the expression and how many characters
+/// it took, the `]` included.
+fn parse_bracket(chars:&[char])->Option<(BracketExpression,usize)>{
+    let mut index = 0;
+    let negated = matches!(chars.first(),Some('!' | '^'));
+    if negated{
+        index += 1;
+    }
+    let mut items = vec![];
+    let start = index;
+    loop {
+        let char = *chars.get(index)?;
+        // a `]` right at the start is part of the set
+        if char == ']' && index > start{
+            return Some((BracketExpression { negated, items },index + 1));
+        }
+        if char == '[' && chars.get(index + 1) == Some(&':'){
+            let rest:String = chars[index + 2..].iter().collect();
+            if let Some(end) = rest.find(":]")
+                && let Some(class) = CharClass::from_name(&rest[..end]){
+                items.push(BracketItem::Class(class));
+                index += 2 + rest[..end].chars().count() + 2;
+                continue;
+            }
+        }
+        let (first,used) = match char {
+            '\\' => (*chars.get(index + 1)?,2),
+            char => (char,1)
+        };
+        index += used;
+        if chars.get(index) == Some(&'-') && chars.get(index + 1).is_some_and(|last| *last != ']'){
+            let (last,used) = match chars[index + 1] {
+                '\\' => (*chars.get(index + 2)?,3),
+                last => (last,2)
+            };
+            index += used;
+            items.push(BracketItem::Range(first,last));
+        }
+        else{
+            items.push(BracketItem::Char(first));
+        }
+    }
+}
+
+fn match_tokens(tokens:&[PatternToken],text:&[char])->bool{
+    let Some((token,rest)) = tokens.split_first() else {
+        return text.is_empty();
+    };
+    match token {
+        PatternToken::AnyString => (0..=text.len()).any(|skipped| match_tokens(rest,&text[skipped..])),
+        PatternToken::AnyChar => !text.is_empty() && match_tokens(rest,&text[1..]),
+        PatternToken::Literal(expected) => text.first() == Some(expected) && match_tokens(rest,&text[1..]),
+        PatternToken::Bracket(bracket) => text.first().is_some_and(|char| bracket.matches(*char)) && match_tokens(rest,&text[1..]),
+        PatternToken::Group(kind,alternatives) => {
+            let matches_alternative = |text:&[char]| alternatives.iter().any(|alternative| match_tokens(alternative,text));
+            match kind {
+                GroupKind::One => (0..=text.len()).any(|end| matches_alternative(&text[..end]) && match_tokens(rest,&text[end..])),
+                GroupKind::ZeroOrOne => match_tokens(rest,text)
+                    || (0..=text.len()).any(|end| matches_alternative(&text[..end]) && match_tokens(rest,&text[end..])),
+                // repeats go through the whole group again, consuming something each time
+                GroupKind::ZeroOrMore => match_tokens(rest,text)
+                    || (1..=text.len()).any(|end| matches_alternative(&text[..end]) && match_tokens(tokens,&text[end..])),
+                GroupKind::OneOrMore => (0..=text.len()).any(|end| {
+                    matches_alternative(&text[..end]) && (match_tokens(rest,&text[end..]) || (end > 0 && match_tokens(tokens,&text[end..])))
+                }),
+                GroupKind::Not => (0..=text.len()).any(|end| !matches_alternative(&text[..end]) && match_tokens(rest,&text[end..]))
+            }
+        }
+    }
+}
+
+/// Adds backslashes before the characters patterns treat specially, so `text` matches
+/// only itself.
+pub fn escape(text:&str)->String{
+    let mut escaped = String::from("");
+    for char in text.chars(){
+        if matches!(char,'*' | '?' | '[' | ']' | '\\' | '(' | ')' | '|'){
+            escaped.push('\\');
+        }
+        escaped.push(char);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn matches(pattern:&str,text:&str)->bool{
+        Pattern::parse(pattern,false).matches(text)
+    }
+
+    fn matches_extended(pattern:&str,text:&str)->bool{
+        Pattern::parse(pattern,true).matches(text)
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*.rs","main.rs"));
+        assert!(!matches("*.rs","main.rsx"));
+        assert!(matches("a?c","abc"));
+        assert!(!matches("a?c","ac"));
+        assert!(matches("*","",));
+        assert!(matches("a*b*c","aXbYbZc"));
+    }
+
+    #[test]
+    fn test_brackets_and_classes() {
+        assert!(matches("[abc]x","bx"));
+        assert!(matches("[!abc]x","dx"));
+        assert!(!matches("[^abc]x","ax"));
+        assert!(matches("[a-c][0-9]","b7"));
+        assert!(matches("[]]","]"));
+        assert!(matches("[[:upper:]][[:digit:]]","Q5"));
+        assert!(!matches("[[:alpha:]]","5"));
+        assert!(matches("[","["));
+        assert!(matches("[a-","[a-"));
+    }
+
+    #[test]
+    fn test_escapes_are_literal() {
+        assert!(matches("\\*","*"));
+        assert!(!matches("\\*","x"));
+        assert!(matches(&escape("a*[b]?"),"a*[b]?"));
+        assert!(!Pattern::parse(&escape("*?@(x)"),true).has_wildcards());
+    }
+
+    #[test]
+    fn test_extglob_groups() {
+        assert!(matches_extended("@(foo|bar).c","bar.c"));
+        assert!(!matches_extended("@(foo|bar).c","baz.c"));
+        assert!(matches_extended("?(x)y","y") && matches_extended("?(x)y","xy") && !matches_extended("?(x)y","xxy"));
+        assert!(matches_extended("*(ab)c","ababc") && matches_extended("*(ab)c","c"));
+        assert!(matches_extended("+(ab|c)d","abcabd") && !matches_extended("+(ab)d","d"));
+        assert!(matches_extended("!(*.rs)","main.c") && !matches_extended("!(*.rs)","main.rs"));
+        assert!(matches_extended("@(a|+([0-9]))","123"));
+        // unclosed or without extglob, the characters are ordinary
+        assert!(matches_extended("@(a","@(a"));
+        assert!(matches("@(a|b)","@(a|b)") && !matches("@(a|b)","a"));
+    }
+}
@@ -2,152 +2,187 @@
 /// syscall and functions implementation for process management.
 pub mod process_impl{
     use std::error::Error;
+    use std::io::{Read, Write};
     use std::path::Path;
-    use std::os::fd::OwnedFd;
-    use std::ffi::{CString,CStr};
+    use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+    use std::ffi::{CStr, CString};
     
+    use nix::errno::Errno;
     use nix::fcntl::{OFlag, open};
+    use nix::libc::{F_DUPFD_CLOEXEC, close, dup2, fcntl};
     use nix::sys::stat::Mode;
-    use nix::unistd::{ dup2_stdin, dup2_stdout, execvp, pipe};
-    use nix::{libc::_exit, sys::wait::waitpid, unistd::{ForkResult, execve, fork, write}};
-
-    use crate::tokenizer::ShellTokens;
+    use nix::sys::wait::WaitStatus;
+    use nix::unistd::{ dup2_stdin, dup2_stdout, execve, execvp, pipe, Pid};
+    use nix::{libc::_exit, sys::wait::waitpid, unistd::{ForkResult, fork, write}};
 
+    /// Which way [`redirect_process`] points the command at its file.
+    #[allow(dead_code, clippy::enum_variant_names)] // only the redirection tests exec this way now
     pub enum IoRedirection{
         InputFromFile,
         OverwriteToFile,
         AppendToFile
     }
-    
-    pub struct ProcessToExec<'a>{
-        
-        /// name of running process
-        process_name:String,
-        
-        /// path of binary of process
-        process_path:&'a Path,
-
-        /// arguments passed to the command
-        process_args:Vec<&'a str>,
-        
-        /// The order in which processes are arranged
-        order:u16
-
-
-    }
-
-
 
-    pub fn spawn_new_process(path:&CStr){
-        match unsafe {fork()}{
-            Ok(ForkResult::Parent { child,..}) =>{
-                println!("Continuing execution in parent process, new child has pid: {}", child);
-                waitpid(child, None).unwrap();
+    /// Forks and runs the program at `path` in the child, with `argv` as its arguments and
+    /// `env` as its environment. `prepare_child` runs in the child first, e.g. to apply
+    /// redirections, and the child exits with status 1 when it returns false. Returns the
+    /// child's pid without waiting for it.
+    pub fn spawn_new_process(path:&CStr,argv:&[CString],env:&[CString],prepare_child:impl FnOnce()->bool)->Result<Pid,Errno>{
+        spawn_subshell(|| {
+            if !prepare_child(){
+                return 1;
             }
-            Ok(ForkResult::Child) => {
-            let _ = write(std::io::stdout(), b"Child: execve starting\n");
-
-            // argv = [program name only]
-            let argv = [path];
+            exec_or_exit(path, argv, env)
+        })
+    }
 
-            // empty env
-            let env: [&CStr; 0] = [];
+    /// Replaces the current process with the program at `path`. On failure prints why and
+    /// exits with 127 when the program does not exist or 126 when it could not be run, like
+    /// other shells.
+    pub fn exec_or_exit(path:&CStr,argv:&[CString],env:&[CString])->!{
+        let err = match execve(path, argv, env) {
+            Ok(never) => match never {},
+            Err(err) => err
+        };
+        let name = argv[0].to_string_lossy();
+        let status = if err == Errno::ENOENT { 127 } else { 126 };
+        let message = format!("hsh: {name}: {}\n",err.desc());
+        let _ = write(std::io::stderr(), message.as_bytes());
+        unsafe { _exit(status) }
+    }
 
-            match execve(path, &argv, &env) {
-                Ok(_) => unreachable!(),
-                Err(err) => {
-                    let msg = format!("execve failed: {}\n", err);
-                    let _ = write(std::io::stderr(), msg.as_bytes());
-                    unsafe { _exit(1) };
-                }
+    /// Waits for `child` and turns how it ended into an exit status, 128 plus the signal
+    /// number for a killed process.
+    pub fn wait_for_process(child:Pid)->i32{
+        loop {
+            match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_,status)) => return status,
+                Ok(WaitStatus::Signaled(_,signal,_)) => return 128 + signal as i32,
+                Ok(_) | Err(Errno::EINTR) => continue,
+                Err(_) => return 127
             }
-            }
-            Err(_) => println!("Fork failed"),
         }
-
     }
 
-    pub fn spawn_and_pipe(prev_pipe:Option<OwnedFd>,curr_index:usize,curr_process_item:&ProcessToExec)->Result<Option<OwnedFd>,Box<dyn Error>>{
-        match unsafe{fork()} {
-            Ok(ForkResult::Parent { child })=>{
-                waitpid(child, None);
-                Ok(None)
+    /// Forks a child that runs `run` and exits with the status it gives, as subshells and
+    /// asynchronous lists do. Returns the child's pid without waiting for it.
+    pub fn spawn_subshell(run:impl FnOnce()->i32)->Result<Pid,Errno>{
+        flush_stdout();
+        match unsafe{fork()}? {
+            ForkResult::Parent { child } => Ok(child),
+            ForkResult::Child => {
+                let status = run();
+                flush_stdout();
+                unsafe { _exit(status) }
             }
-            Ok(ForkResult::Child)=>{
-                let c_string_args:Vec<CString> = curr_process_item.process_args.iter()
-                .map(|s| CString::new(*s).unwrap())
-                .collect();
-                
-                let c_str_args: Vec<&CStr> = c_string_args
-                .iter()
-                .map(|s| s.as_c_str())
-                .collect();
+        }
+    }
 
-            
-                // match pipe(){
-                //     Ok(pipe_ends)=>{
-                //         let (receive_end_pipe,send_end_pipe) = (pipe_ends.0,pipe_ends.1);
-                //             dup2_stdin(send_end_pipe)?;
-                //         }
-                //         Err(errno)=>{
-                //             println!("{}",errno);
-                            
-                //         }
-                // }
+    /// Forks one member of a pipeline. Its stdin comes from the previous member's pipe and,
+    /// unless it is the last member, its stdout goes into a new pipe whose read end is
+    /// returned for the next member. `run` gives the child's exit status.
+    pub fn spawn_and_pipe(prev_pipe:Option<OwnedFd>,is_last:bool,run:impl FnOnce()->i32)->Result<(Pid,Option<OwnedFd>),Errno>{
+        let (receive_end_pipe,send_end_pipe) = match is_last {
+            true => (None,None),
+            false => {
                 let (receive_end_pipe,send_end_pipe) = pipe()?;
-                dup2_stdout(send_end_pipe)?;
-                if curr_index != 0{
-
-                    if let Some(val) = prev_pipe{
-                        dup2_stdin(val)?;
-                    }
-                    // match prev_pipe{
-                    //     Some(val)=>dup2_stdin(val),
-                    //     None => {
-                    //         eprintln!("Error!");
-                    //         Ok(())
-                    //     }
-                        
-                    // }
-                }
-
-
-                
-                    
-
-                execvp(CString::new(curr_process_item.process_path.to_str().expect("Failed to convert Cstring!"))?.as_c_str(),
-                &c_str_args);
-                    
-                Ok(Some(receive_end_pipe))
+                (Some(receive_end_pipe),Some(send_end_pipe))
             }
-            Err(err)=>{
-                println!("{}",err);
-                Ok(None)
+        };
+        // the child must not hold the read end of its own output, or writers would never
+        // see the reader go away
+        let receive_end_raw = receive_end_pipe.as_ref().map(|fd| fd.as_raw_fd());
+        let child = spawn_subshell(move || {
+            if let Some(raw_fd) = receive_end_raw{
+                unsafe { close(raw_fd) };
             }
-        }
+            if let Some(val) = prev_pipe
+                && dup2_stdin(val).is_err(){
+                return 1;
+            }
+            if let Some(send_end_pipe) = send_end_pipe
+                && dup2_stdout(send_end_pipe).is_err(){
+                return 1;
+            }
+            run()
+        })?;
+        Ok((child,receive_end_pipe))
     }
 
+    /// Runs `run` in a forked child with its stdout going into a pipe, as command
+    /// substitutions do. Reads everything the child writes, then waits for it, giving the
+    /// output along with the child's exit status.
+    pub fn capture_output(run:impl FnOnce()->i32)->Result<(Vec<u8>,i32),Errno>{
+        let (receive_end_pipe,send_end_pipe) = pipe()?;
+        let receive_end_raw = receive_end_pipe.as_raw_fd();
+        let child = spawn_subshell(move || {
+            unsafe { close(receive_end_raw) };
+            if dup2_stdout(send_end_pipe).is_err(){
+                return 1;
+            }
+            run()
+        })?;
+        // the parent's copy of the write end is dropped by now, so reading ends when the
+        // child and anything it started are done writing
+        let mut output = vec![];
+        let read = std::fs::File::from(receive_end_pipe).read_to_end(&mut output);
+        let status = wait_for_process(child);
+        read.map_err(|err| err.raw_os_error().map_or(Errno::EIO, Errno::from_raw))?;
+        Ok((output,status))
+    }
 
-    // fn to perform piping
-    pub fn perform_piping(process_list:Vec<ProcessToExec>)->Result<(),Box<dyn Error>>{
-        let process_iterator = process_list.iter();
-
+    /// Runs `stage_count` pipeline members connected by pipes, `run_stage` being called in
+    /// each child with the member's index. Returns the pids in pipeline order.
+    pub fn perform_piping(stage_count:usize,mut run_stage:impl FnMut(usize)->i32)->Result<Vec<Pid>,Errno>{
         let mut prev_pipe:Option<OwnedFd> = None;
-        //use fork to 
-        for (index,item) in process_iterator.enumerate(){
-            let next_pipe = spawn_and_pipe(prev_pipe,index,item)?;
-                prev_pipe = next_pipe;
+        let mut children = vec![];
+        for index in 0..stage_count{
+            let (child,next_pipe) = spawn_and_pipe(prev_pipe, index + 1 == stage_count, || run_stage(index))?;
+            children.push(child);
+            prev_pipe = next_pipe;
         }
-        Ok(())
+        Ok(children)
     }
 
+    /// Makes `target` refer to what `fd` refers to, closing whatever `target` was before.
+    pub fn duplicate_fd(fd:RawFd,target:RawFd)->Result<(),Errno>{
+        if fd == target{
+            return Ok(());
+        }
+        Errno::result(unsafe { dup2(fd, target) }).map(drop)
+    }
+
+    /// Copies `fd` to a number above the ones scripts use, closed on exec, so it can be put
+    /// back after a redirection. None when `fd` was not open.
+    pub fn save_fd(fd:RawFd)->Option<RawFd>{
+        Errno::result(unsafe { fcntl(fd, F_DUPFD_CLOEXEC, 10) }).ok()
+    }
+
+    /// Puts back an fd saved by [`save_fd`], or closes `fd` when it was not open before.
+    pub fn restore_fd(fd:RawFd,saved:Option<RawFd>){
+        match saved {
+            Some(saved) => {
+                let _ = duplicate_fd(saved, fd);
+                unsafe { close(saved) };
+            },
+            None => {
+                unsafe { close(fd) };
+            }
+        }
+    }
 
+    /// Writes out anything buffered for stdout, so it is not lost or written twice when the
+    /// process forks or its fd 1 changes.
+    pub fn flush_stdout(){
+        let _ = std::io::stdout().flush();
+    }
 
     #[derive(Clone)]
     pub enum RedirectionFileType{
         ReadOnly,
         WriteOnly,
-        Append
+        Append,
+        ReadWrite
     }
 
 
@@ -166,6 +201,10 @@ pub fn open_file_for_redirection(
         },
         RedirectionFileType::ReadOnly => {
             (OFlag::O_RDONLY, Mode::empty())
+        },
+        RedirectionFileType::ReadWrite => {
+            (OFlag::O_RDWR | OFlag::O_CREAT,
+             Mode::S_IRUSR | Mode::S_IWUSR)
         }
     };
     
@@ -173,6 +212,7 @@ pub fn open_file_for_redirection(
     Ok(fd)
 }
 
+#[allow(dead_code)]
 pub fn redirect_process(
     file_path: &Path,
     flag: IoRedirection,
@@ -246,7 +286,6 @@ mod syscall_tests{
     
     use crate::process::process_impl::{open_file_for_redirection,RedirectionFileType};
 
-    use super::*;
     use std::fs::{File, read_to_string};
     use std::io::Write;
     use tempfile::tempdir;
@@ -274,7 +313,7 @@ fn test_input_from_file_cat() {
     // Create input
     {
         let mut f = File::create(&input_path).unwrap();
-        write!(f, "hello from file\n").unwrap();
+        writeln!(f, "hello from file").unwrap();
     }
 
     match unsafe { fork() } {
@@ -387,7 +426,7 @@ fn test_input_from_file_cat() {
         // Pre-create file with old content
         {
             let mut f = File::create(&output_path).unwrap();
-            write!(f, "OLD CONTENT THAT SHOULD BE REMOVED\n").unwrap();
+            writeln!(f, "OLD CONTENT THAT SHOULD BE REMOVED").unwrap();
         }
 
         match unsafe { fork() } {
@@ -427,7 +466,7 @@ fn test_input_from_file_cat() {
         // Create initial content
         {
             let mut f = File::create(&output_path).unwrap();
-            write!(f, "line1\n").unwrap();
+            writeln!(f, "line1").unwrap();
         }
 
         match unsafe { fork() } {
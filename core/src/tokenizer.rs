@@ -1,112 +1,1046 @@
-use std::error::Error;
+use crate::error::TokenizeError;
 
 
+/// Where a token or AST node came from: byte range in the source plus the 1-based line
+/// and column it starts at.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct Span{
+    pub start:usize,
+    pub end:usize,
+    pub line:usize,
+    pub column:usize
+}
+
+/// A token along with its place in the source.
+#[derive(Debug,PartialEq,Eq)]
+pub struct Token{
+    pub kind:ShellTokens,
+    pub span:Span
+}
+
 /// Classes of characters possible
 #[derive(Debug,PartialEq,Eq)]
 pub enum ShellTokens{
-    Word(String), //Normal characters apart from reserved ones like $,|,&...
+    Word(Word), //Any run of characters apart from operators, blanks and quotes
     Pipe, // | used to redirect output to another process
-    RedirectAsInput, // > 
-    RedirectAsOutput, // <
-    DoubleQuotes, // "
-    SingleQuotes, // '
+    PipeWithError, // |& pipes stderr along with stdout
+    And, // && runs next command only on success
+    Or, // || runs next command only on failure
+    Semicolon, // ; runs commands one after another
+    DoubleSemicolon, // ;; ends a case item
+    SemicolonAnd, // ;& falls through to the next case item
+    DoubleSemicolonAnd, // ;;& goes on testing the next case patterns
+    Background, // & runs command asynchronously
+    // Redirections carry the fd written before them, e.g. Some(2) for 2>
+    RedirectOutput(Option<u32>), // >
+    AppendOutput(Option<u32>), // >>
+    Clobber(Option<u32>), // >| overwrites even with noclobber
+    DuplicateOutput(Option<u32>), // >&
+    RedirectInput(Option<u32>), // <
+    ReadWrite(Option<u32>), // <> opens file for reading and writing
+    DuplicateInput(Option<u32>), // <&
+    HereDoc(Option<u32>), // <<
+    HereDocStripTabs(Option<u32>), // <<- also strips leading tabs from the body
+    HereDocument(HereDocument), // body of a here-document, takes the place of its delimiter word
+    HereString(Option<u32>), // <<<
+    RedirectOutputAndError, // &> sends stdout and stderr to file
+    AppendOutputAndError, // &>> appends stdout and stderr to file
     ParenthesesOpen, // (
     ParenthesesClose, // )
-    Comment(String), // #
+    Comment(String), // # up to end of line, only kept when asked for
     // LogicalNot, // !
-    Assignment, // =
-    Escape, // \ makes next char literal
-    Variable(String), // $
-    ReservedWord(ReservedWord), // if,else,elif,! etc
+    ReservedWord(ReservedWord), // if,else,elif,! etc, only where a command could start
     Whitespace, // Single 
+    Newline, // ends a command like ;
 }
 
-#[derive(Debug,PartialEq,Eq)]
-enum ReservedWord{
-    // If,
-    // Else,
-    // Elif,
-    // While,
-    // For,
-    // BracketOpen,
-    // BracketClose,
-    // Exclamation
+/// Body of a here-document, read from the lines after the command up to the delimiter line.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct HereDocument{
+    pub body:String,
+    /// Any quoting in the delimiter, like `<<'EOF'`, turns off expansion of the body.
+    pub quoted:bool
+}
+
+/// A single shell word made of adjacent parts, e.g. `a"b c"'$d'` is one word of three parts.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub struct Word{
+    pub parts:Vec<WordPart>
+}
+
+impl WordPart{
+    /// Shell source that produces this part, with quoting put back.
+    pub fn source_text(&self)->String{
+        match self {
+            WordPart::Literal(text) => text.clone(),
+            WordPart::SingleQuoted(text) => format!("'{text}'"),
+            WordPart::DoubleQuoted(parts) => format!("\"{}\"", parts.iter().map(WordPart::source_text).collect::<String>()),
+            WordPart::Variable(name) => format!("${name}"),
+            WordPart::Positional(position) => format!("${position}"),
+            WordPart::SpecialParameter(char) => format!("${char}"),
+            WordPart::BracedParameter(text) => format!("${{{text}}}"),
+            WordPart::Arithmetic(expression) => format!("$(({expression}))"),
+            WordPart::CommandSubstitution(command) => format!("$({command})"),
+            WordPart::Escaped(char) => format!("\\{char}"),
+            WordPart::GlobChar(char) => char.to_string(),
+            WordPart::Tilde => String::from("~"),
+        }
+    }
+}
+
+impl From<&str> for Word{
+    fn from(literal:&str)->Self{
+        Word{parts:vec![WordPart::Literal(literal.to_string())]}
+    }
+}
+
+/// Pieces a word is built from. Quoting is kept so later expansion stages know what to expand.
+#[derive(Debug,PartialEq,Eq,Clone)]
+pub enum WordPart{
+    /// Unquoted characters.
+    Literal(String),
+    /// Text between '...', taken fully literally.
+    SingleQuoted(String),
+    /// Text between "...", only variables, substitutions and escapes are special inside.
+    DoubleQuoted(Vec<WordPart>),
+    /// `$name`
+    Variable(String),
+    /// `$1` to `$9`. Higher positions need braces, `$10` is `$1` followed by `0`.
+    Positional(usize),
+    /// `$?`, `$#`, `$@`, `$*`, `$$`, `$!`, `$-` and `$0`
+    SpecialParameter(char),
+    /// `${...}`, holds the exact source between the braces, e.g. `name:-word` or `#name`.
+    BracedParameter(String),
+    /// `$((...))`, holds the source of the expression.
+    Arithmetic(String),
+    /// `$(...)` or `` `...` ``, holds the source of the inner command. Escapes that only
+    /// exist because of the backticks are already removed.
+    CommandSubstitution(String),
+    /// Character made literal by a backslash.
+    Escaped(char),
+    /// Unquoted `*`, `?`, `[` or `]` for pathname expansion, or a character of an extglob
+    /// group: its `?(`, `*(`, `+(`, `@(` or `!(` opening, `|` or `)`.
+    GlobChar(char),
+    /// Unquoted `~` for tilde expansion.
+    Tilde,
+}
+
+/// Words with a grammatical meaning when they appear where a command could start.
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+pub enum ReservedWord{
+    If,
+    Then,
+    Elif,
+    Else,
+    Fi,
+    While,
+    Until,
+    For,
+    In,
+    Do,
+    Done,
+    Case,
+    Esac,
+    BracketOpen, // {
+    BracketClose, // }
+    Exclamation, // !
+    DoubleBracketOpen, // [[
+    DoubleBracketClose, // ]]
+    Function,
+    Select,
+    Time
+}
+
+impl ReservedWord{
+    const ALL:[ReservedWord;21] = [
+        ReservedWord::If, ReservedWord::Then, ReservedWord::Elif, ReservedWord::Else, ReservedWord::Fi,
+        ReservedWord::While, ReservedWord::Until, ReservedWord::For, ReservedWord::In, ReservedWord::Do,
+        ReservedWord::Done, ReservedWord::Case, ReservedWord::Esac, ReservedWord::BracketOpen,
+        ReservedWord::BracketClose, ReservedWord::Exclamation, ReservedWord::DoubleBracketOpen,
+        ReservedWord::DoubleBracketClose, ReservedWord::Function, ReservedWord::Select, ReservedWord::Time
+    ];
+
+    pub fn as_str(&self)->&'static str{
+        match self {
+            ReservedWord::If => "if",
+            ReservedWord::Then => "then",
+            ReservedWord::Elif => "elif",
+            ReservedWord::Else => "else",
+            ReservedWord::Fi => "fi",
+            ReservedWord::While => "while",
+            ReservedWord::Until => "until",
+            ReservedWord::For => "for",
+            ReservedWord::In => "in",
+            ReservedWord::Do => "do",
+            ReservedWord::Done => "done",
+            ReservedWord::Case => "case",
+            ReservedWord::Esac => "esac",
+            ReservedWord::BracketOpen => "{",
+            ReservedWord::BracketClose => "}",
+            ReservedWord::Exclamation => "!",
+            ReservedWord::DoubleBracketOpen => "[[",
+            ReservedWord::DoubleBracketClose => "]]",
+            ReservedWord::Function => "function",
+            ReservedWord::Select => "select",
+            ReservedWord::Time => "time",
+        }
+    }
+
+    /// Looks up an unquoted word. Quoted words like `"if"` are never reserved.
+    fn from_word(word:&Word)->Option<ReservedWord>{
+        // `[[` and `]]` come out of the tokenizer as glob characters
+        let mut text = String::from("");
+        for part in &word.parts{
+            match part {
+                WordPart::Literal(literal) => text.push_str(literal),
+                WordPart::GlobChar(char) => text.push(*char),
+                _ => return None
+            }
+        }
+        ReservedWord::ALL.into_iter().find(|reserved| reserved.as_str() == text)
+    }
+}
+
+/// What the grammar allows for the next word, which decides whether it may be a reserved word.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum WordPosition{
+    /// First word of a command, where every reserved word is recognized.
+    Command,
+    /// Arguments, where nothing is reserved.
+    Argument,
+    /// The name after `for`/`select`, followed by `in` or `do`.
+    LoopName,
+    AfterLoopName,
+    /// The word after `case`, followed by `in`.
+    CaseSubject,
+    AfterCaseSubject,
+    /// The name after `function`.
+    FunctionName
+}
+
+/// Tracks the grammatical position across tokens so `echo if` keeps `if` a plain argument.
+struct ReservedWordTracker{
+    position:WordPosition,
+    /// The next word is the target of a redirection and leaves the position alone.
+    redirection_target:bool,
+    /// Nesting of `[[`, inside which only `]]` is reserved.
+    conditional_depth:usize
+}
+
+impl ReservedWordTracker{
+    fn new()->Self{
+        ReservedWordTracker { position: WordPosition::Command, redirection_target: false, conditional_depth: 0 }
+    }
+
+    fn classify(&mut self,kind:ShellTokens)->ShellTokens{
+        let word = match kind {
+            ShellTokens::Word(word) => word,
+            ShellTokens::Whitespace | ShellTokens::Comment(_) => return kind,
+            _ => {
+                self.redirection_target = is_redirection(&kind);
+                if !self.redirection_target && self.conditional_depth == 0{
+                    self.position = WordPosition::Command;
+                }
+                return kind;
+            }
+        };
+        if self.redirection_target{
+            self.redirection_target = false;
+            return ShellTokens::Word(word);
+        }
+        let reserved = ReservedWord::from_word(&word);
+        if self.conditional_depth > 0{
+            if reserved == Some(ReservedWord::DoubleBracketClose){
+                self.conditional_depth -= 1;
+                self.position = WordPosition::Argument;
+                return ShellTokens::ReservedWord(ReservedWord::DoubleBracketClose);
+            }
+            return ShellTokens::Word(word);
+        }
+        let (recognized,next) = match (self.position,reserved) {
+            (WordPosition::Command,Some(reserved)) => (Some(reserved), match reserved {
+                ReservedWord::For | ReservedWord::Select => WordPosition::LoopName,
+                ReservedWord::Case => WordPosition::CaseSubject,
+                ReservedWord::Function => WordPosition::FunctionName,
+                ReservedWord::Fi | ReservedWord::Done | ReservedWord::Esac | ReservedWord::BracketClose
+                    | ReservedWord::In | ReservedWord::DoubleBracketClose => WordPosition::Argument,
+                _ => WordPosition::Command
+            }),
+            (WordPosition::LoopName,_) => (None,WordPosition::AfterLoopName),
+            (WordPosition::AfterLoopName,Some(ReservedWord::In)) => (reserved,WordPosition::Argument),
+            (WordPosition::AfterLoopName,Some(ReservedWord::Do)) => (reserved,WordPosition::Command),
+            (WordPosition::CaseSubject,_) => (None,WordPosition::AfterCaseSubject),
+            // case patterns sit where commands would, so `esac` is found there
+            (WordPosition::AfterCaseSubject,Some(ReservedWord::In)) => (reserved,WordPosition::Command),
+            (WordPosition::FunctionName,_) => (None,WordPosition::Command),
+            _ => (None,WordPosition::Argument)
+        };
+        self.position = next;
+        match recognized {
+            Some(reserved) => {
+                if reserved == ReservedWord::DoubleBracketOpen{
+                    self.conditional_depth += 1;
+                }
+                ShellTokens::ReservedWord(reserved)
+            },
+            None => ShellTokens::Word(word)
+        }
+    }
+}
+
+fn is_redirection(kind:&ShellTokens)->bool{
+    matches!(kind,
+        ShellTokens::RedirectOutput(_) | ShellTokens::AppendOutput(_) | ShellTokens::Clobber(_)
+        | ShellTokens::DuplicateOutput(_) | ShellTokens::RedirectInput(_) | ShellTokens::ReadWrite(_)
+        | ShellTokens::DuplicateInput(_) | ShellTokens::HereDoc(_) | ShellTokens::HereDocStripTabs(_)
+        | ShellTokens::HereString(_)
+        | ShellTokens::RedirectOutputAndError | ShellTokens::AppendOutputAndError)
+}
+
+/// Character iterator that keeps track of where in the input it is, so tokens get spans.
+#[derive(Clone)]
+struct CharIter<'a>{
+    chars:std::iter::Peekable<std::str::Chars<'a>>,
+    offset:usize,
+    line:usize,
+    column:usize
+}
+
+impl<'a> CharIter<'a>{
+    fn new(input:&'a str)->Self{
+        CharIter { chars: input.chars().peekable(), offset: 0, line: 1, column: 1 }
+    }
+
+    fn peek(&mut self)->Option<char>{
+        self.chars.peek().copied()
+    }
+
+    fn next_if(&mut self,func:impl FnOnce(&char)->bool)->Option<char>{
+        match self.peek() {
+            Some(char) if func(&char) => self.next(),
+            _ => None
+        }
+    }
+
+    fn next_if_eq(&mut self,expected:&char)->Option<char>{
+        self.next_if(|char| char == expected)
+    }
+
+    /// Empty span at the current position, later closed with `end_span`.
+    fn start_span(&self)->Span{
+        Span { start: self.offset, end: self.offset, line: self.line, column: self.column }
+    }
+
+    /// Extends `span` up to the current position.
+    fn end_span(&self,span:Span)->Span{
+        Span { end: self.offset, ..span }
+    }
 }
 
+impl Iterator for CharIter<'_>{
+    type Item = char;
 
-pub fn tokenize_input_intermediate(input:&str)->Vec<ShellTokens>{
+    fn next(&mut self)->Option<char>{
+        let char = self.chars.next()?;
+        self.offset += char.len_utf8();
+        if char == '\n'{
+            self.line += 1;
+            self.column = 1;
+        }
+        else{
+            self.column += 1;
+        }
+        Some(char)
+    }
+}
+
+/// Knobs for how input is tokenized.
+#[derive(Debug,Clone,Default)]
+pub struct TokenizerOptions{
+    /// Emit `ShellTokens::Comment` tokens instead of dropping comments, e.g. for a formatter.
+    pub keep_comments:bool
+}
 
-    let mut output_tokens:Vec<ShellTokens> = vec![];
+pub fn tokenize_input_intermediate(input:&str)->Result<Vec<Token>,TokenizeError>{
+    tokenize_with_options(input, &TokenizerOptions::default())
+}
+
+pub fn tokenize_with_options(input:&str,options:&TokenizerOptions)->Result<Vec<Token>,TokenizeError>{
+
+    let mut output_tokens:Vec<Token> = vec![];
     // Iterate character wise 
-    let mut iterator = input.chars().peekable();
+    let mut iterator = CharIter::new(input);
+    let mut reserved_words = ReservedWordTracker::new();
+    // here-document bodies start on the line after their `<<`, so delimiter words wait for a
+    // newline along with whether tabs get stripped
+    let mut strip_next_delimiter:Option<bool> = None;
+    let mut pending_here_documents:Vec<(usize,bool)> = vec![];
 
     while let Some(char) = iterator.peek(){
-        match char {
-                    '$' => {
-                        output_tokens.push(ShellTokens::Variable(handle_variable(&mut iterator)));
+        let span = iterator.start_span();
+        let kind = match char {
+                    '\\' if at_line_continuation(&iterator) => {
+                        skip_line_continuation(&mut iterator)?;
                         continue;
                     },
-                    '=' =>{
-                        output_tokens.push(ShellTokens::Assignment);
+                    char if char.is_ascii_digit() && at_io_number(&iterator) => {
+                        let fd = handle_io_number(&mut iterator);
+                        handle_operator(&mut iterator, Some(fd))
                     },
-                    '|' => {
-                        output_tokens.push(ShellTokens::Pipe);
+                    char if starts_word(char) => {
+                        ShellTokens::Word(handle_word(&mut iterator)?)
                     },
-                    '>' => {
-                        output_tokens.push(ShellTokens::RedirectAsInput);
+                    '|' | '&' | ';' | '<' | '>' => {
+                        handle_operator(&mut iterator, None)
                     },
-                    '<' => {
-                        output_tokens.push(ShellTokens::RedirectAsOutput);
+                    ' ' | '\t' => {
+                        iterator.next();
+                        ShellTokens::Whitespace
                     },
-                    ' ' => {
-                        output_tokens.push(ShellTokens::Whitespace);
-                    },
-                    '\'' => {
-                        output_tokens.push(ShellTokens::SingleQuotes);
-                    },
-                    char if char.is_alphanumeric() => {
-                        output_tokens.push(ShellTokens::Word(handle_unreserved_chars(&mut iterator)));
-                        continue;
+                    '\n' => {
+                        iterator.next();
+                        ShellTokens::Newline
                     },
                     '(' => {
-                        output_tokens.push(ShellTokens::ParenthesesOpen);
+                        iterator.next();
+                        ShellTokens::ParenthesesOpen
                     },
                     ')' => {
-                        output_tokens.push(ShellTokens::ParenthesesClose);
-                    },
-                    '\"' => {
-                        output_tokens.push(ShellTokens::DoubleQuotes);
+                        iterator.next();
+                        ShellTokens::ParenthesesClose
                     },
+                    // only reached at the start of a word, a `#` inside one is literal
                     '#' => {
-                        output_tokens.push(ShellTokens::Comment(handle_comment_line(&mut iterator)));
-                        continue;
+                        let comment = handle_comment_line(&mut iterator);
+                        if !options.keep_comments{
+                            continue;
+                        }
+                        ShellTokens::Comment(comment)
                     }
                     _ => {
-                        eprintln!("Invalid!");
+                        iterator.next();
+                        return Err(TokenizeError::InvalidCharacter { char, span: iterator.end_span(span) });
                     }
 
+                };
+        let kind = reserved_words.classify(kind);
+        match kind {
+            ShellTokens::HereDoc(_) => strip_next_delimiter = Some(false),
+            ShellTokens::HereDocStripTabs(_) => strip_next_delimiter = Some(true),
+            ShellTokens::Word(_) => {
+                if let Some(strip_tabs) = strip_next_delimiter.take(){
+                    pending_here_documents.push((output_tokens.len(),strip_tabs));
+                }
+            },
+            _ => {}
+        }
+        let at_newline = kind == ShellTokens::Newline;
+        output_tokens.push(Token { kind, span: iterator.end_span(span) });
+        if at_newline{
+            for (index,strip_tabs) in pending_here_documents.drain(..){
+                handle_here_document(&mut iterator, &mut output_tokens[index], strip_tabs)?;
+            }
+        }
+    }
+    if !pending_here_documents.is_empty(){
+        return Err(TokenizeError::IncompleteInput { span: iterator.start_span() });
+    }
+
+
+    Ok(output_tokens)
+}
+
+/// Reads here-document lines up to the delimiter line and puts the document in place of
+/// the delimiter word token.
+fn handle_here_document(iter:&mut CharIter,delimiter_token:&mut Token,strip_tabs:bool)->Result<(),TokenizeError>{
+    let ShellTokens::Word(word) = &delimiter_token.kind else {
+        unreachable!("here-document delimiters are words");
+    };
+    let (delimiter,quoted) = here_document_delimiter(word);
+    let span = iter.start_span();
+    let mut body = String::from("");
+    loop {
+        let mut line = String::from("");
+        while let Some(char) = iter.next_if(|char| *char != '\n'){
+            line.push(char);
+        }
+        let line_ended = iter.next().is_some();
+        let line = if strip_tabs { line.trim_start_matches('\t') } else { line.as_str() };
+        if line == delimiter{
+            break;
+        }
+        if !line_ended{
+            return Err(TokenizeError::IncompleteInput { span: iter.end_span(span) });
+        }
+        body.push_str(line);
+        body.push('\n');
+    }
+    delimiter_token.kind = ShellTokens::HereDocument(HereDocument { body, quoted });
+    Ok(())
+}
+
+/// The delimiter with quotes removed, and whether any quoting was used.
+fn here_document_delimiter(word:&Word)->(String,bool){
+    let mut delimiter = String::from("");
+    let mut quoted = false;
+    for part in &word.parts{
+        match part {
+            WordPart::SingleQuoted(text) => {
+                delimiter.push_str(text);
+                quoted = true;
+            },
+            WordPart::DoubleQuoted(inner) => {
+                delimiter.push_str(&here_document_delimiter(&Word { parts: inner.clone() }).0);
+                quoted = true;
+            },
+            WordPart::Escaped(char) => {
+                delimiter.push(*char);
+                quoted = true;
+            },
+            part => delimiter.push_str(&part.source_text())
+        }
+    }
+    (delimiter,quoted)
+}
+
+/// Characters that may begin a word. A `#` there starts a comment instead.
+fn starts_word(char:char)->bool{
+    !is_word_delimiter(char) && char != '#' && !is_invalid(char)
+}
+
+/// NUL can never be passed on to a command, so it is rejected wherever it shows up.
+fn is_invalid(char:char)->bool{
+    char == '\0'
+}
+
+fn invalid_character(iter:&mut CharIter,char:char)->TokenizeError{
+    let span = iter.start_span();
+    iter.next();
+    TokenizeError::InvalidCharacter { char, span: iter.end_span(span) }
+}
+
+/// Blanks and operator characters end a word, everything else belongs to it.
+fn is_word_delimiter(char:char)->bool{
+    matches!(char,' ' | '\t' | '\n' | '|' | '&' | ';' | '<' | '>' | '(' | ')')
+}
+
+/// Checks whether the iterator sits on the start of an extglob group such as `@(`.
+fn at_extglob_group(iter:&CharIter)->bool{
+    let mut ahead = iter.clone();
+    ahead.next();
+    ahead.peek() == Some('(')
+}
+
+/// Checks whether the iterator sits on digits directly followed by `<` or `>`, like the 2 in `2>`.
+fn at_io_number(iter:&CharIter)->bool{
+    let mut ahead = iter.clone();
+    while ahead.next_if(|char| char.is_ascii_digit()).is_some(){}
+    matches!(ahead.peek(),Some('<' | '>'))
+}
+
+fn handle_io_number(iter:&mut CharIter)->u32{
+    let mut digits = String::from("");
+    while let Some(digit) = iter.next_if(|char| char.is_ascii_digit()){
+        digits.push(digit);
+    }
+    // fds that don't fit are left to fail when the redirection is applied
+    digits.parse().unwrap_or(u32::MAX)
+}
+
+/// Reads the longest operator starting at the iterator. `fd` is the io number written
+/// before a redirection, if any.
+fn handle_operator(iter:&mut CharIter,fd:Option<u32>)->ShellTokens{
+    let mut eat = |expected:char| iter.next_if_eq(&expected).is_some();
+    if eat('|'){
+        return if eat('|') { ShellTokens::Or }
+            else if eat('&') { ShellTokens::PipeWithError }
+            else { ShellTokens::Pipe };
+    }
+    if eat('&'){
+        return if eat('&') { ShellTokens::And }
+            else if eat('>') {
+                if eat('>') { ShellTokens::AppendOutputAndError } else { ShellTokens::RedirectOutputAndError }
+            }
+            else { ShellTokens::Background };
+    }
+    if eat(';'){
+        return if eat(';') {
+                if eat('&') { ShellTokens::DoubleSemicolonAnd } else { ShellTokens::DoubleSemicolon }
+            }
+            else if eat('&') { ShellTokens::SemicolonAnd }
+            else { ShellTokens::Semicolon };
+    }
+    if eat('>'){
+        return if eat('>') { ShellTokens::AppendOutput(fd) }
+            else if eat('&') { ShellTokens::DuplicateOutput(fd) }
+            else if eat('|') { ShellTokens::Clobber(fd) }
+            else { ShellTokens::RedirectOutput(fd) };
+    }
+    // only `<` is left
+    eat('<');
+    if eat('<'){
+        if eat('<') { ShellTokens::HereString(fd) }
+        else if eat('-') { ShellTokens::HereDocStripTabs(fd) }
+        else { ShellTokens::HereDoc(fd) }
+    }
+    else if eat('&') { ShellTokens::DuplicateInput(fd) }
+    else if eat('>') { ShellTokens::ReadWrite(fd) }
+    else { ShellTokens::RedirectInput(fd) }
+}
+
+/// Checks whether the iterator sits on a backslash-newline pair.
+fn at_line_continuation(iter:&CharIter)->bool{
+    let mut ahead = iter.clone();
+    ahead.next() == Some('\\') && ahead.next() == Some('\n')
+}
+
+/// Drops a backslash-newline pair so the two lines are joined. Input ending right after it
+/// is incomplete and the caller should read another line.
+fn skip_line_continuation(iter:&mut CharIter)->Result<(),TokenizeError>{
+    let span = iter.start_span();
+    iter.next();
+    iter.next();
+    match iter.peek() {
+        Some(_) => Ok(()),
+        None => Err(TokenizeError::IncompleteInput { span: iter.end_span(span) })
+    }
+}
+
+/// Tells the REPL or script reader whether `input` stops in the middle of a command, e.g.
+/// inside quotes or after a trailing backslash-newline, so another line should be read and appended.
+pub fn needs_more_input(input:&str)->bool{
+    match tokenize_input_intermediate(input) {
+        Err(err) => err.is_incomplete(),
+        Ok(_) => false
+    }
+}
+
+/// Collects adjacent unquoted, quoted and `$` parts into one word.
+fn handle_word(iter:&mut CharIter)->Result<Word,TokenizeError>{
+    let mut parts:Vec<WordPart> = vec![];
+    // extglob groups being read, inside which `|` and `)` belong to the word
+    let mut group_depth = 0;
+    while let Some(char) = iter.peek(){
+        match char {
+            '?' | '*' | '+' | '@' | '!' if at_extglob_group(iter) => {
+                parts.push(WordPart::GlobChar(char));
+                parts.push(WordPart::GlobChar('('));
+                iter.next();
+                iter.next();
+                group_depth += 1;
+            },
+            '|' | ')' if group_depth > 0 => {
+                parts.push(WordPart::GlobChar(char));
+                iter.next();
+                if char == ')'{
+                    group_depth -= 1;
+                }
+            },
+            '\'' => {
+                parts.push(WordPart::SingleQuoted(handle_single_quotes(iter)?));
+            },
+            '"' => {
+                parts.push(WordPart::DoubleQuoted(handle_double_quotes(iter)?));
+            },
+            '$' => {
+                let part = handle_dollar(iter)?;
+                push_part(&mut parts, part);
+            },
+            '`' => {
+                parts.push(handle_backtick(iter, false)?);
+            },
+            char if is_invalid(char) => {
+                return Err(invalid_character(iter, char));
+            },
+            '\\' if at_line_continuation(iter) => {
+                skip_line_continuation(iter)?;
+            },
+            '\\' => {
+                iter.next();
+                match iter.next() {
+                    Some(escaped) => parts.push(WordPart::Escaped(escaped)),
+                    // nothing left to escape, keep the backslash itself
+                    None => push_part(&mut parts, WordPart::Literal(String::from("\\")))
+                }
+            },
+            '*' | '?' | '[' | ']' => {
+                parts.push(WordPart::GlobChar(char));
+                iter.next();
+            },
+            '~' => {
+                parts.push(WordPart::Tilde);
+                iter.next();
+            },
+            char if !is_word_delimiter(char) => {
+                push_part(&mut parts, WordPart::Literal(handle_unreserved_chars(iter)));
+            },
+            _ => break
+        }
+    }
+    Ok(Word{parts})
+}
+
+/// Reads from the opening `'` up to the closing one. Nothing inside single quotes is special.
+fn handle_single_quotes(iter:&mut CharIter)->Result<String,TokenizeError>{
+    let span = iter.start_span();
+    iter.next();
+    let mut quoted = String::from("");
+    while let Some(char) = iter.peek(){
+        match char {
+            '\'' => {
+                iter.next();
+                return Ok(quoted);
+            },
+            char if is_invalid(char) => {
+                return Err(invalid_character(iter, char));
+            },
+            _ => {
+                quoted.push(char);
+                iter.next();
+            }
+        }
+    }
+    Err(TokenizeError::UnterminatedQuote { quote: '\'', span: iter.end_span(span) })
+}
+
+/// Reads from the opening `"` up to the closing one, keeping `$` expansions and backslash
+/// escapes as separate parts.
+fn handle_double_quotes(iter:&mut CharIter)->Result<Vec<WordPart>,TokenizeError>{
+    let span = iter.start_span();
+    iter.next();
+    let parts = handle_expandable_text(iter, true)?;
+    match iter.next() {
+        Some('"') => Ok(parts),
+        _ => Err(TokenizeError::UnterminatedQuote { quote: '"', span: iter.end_span(span) })
+    }
+}
+
+/// Reads the body of a here-document whose delimiter was not quoted. It is read like
+/// double-quoted text, except that `"` is an ordinary character even after a backslash.
+pub fn tokenize_here_document(body:&str)->Result<Vec<WordPart>,TokenizeError>{
+    handle_expandable_text(&mut CharIter::new(body), false)
+}
+
+/// Reads text in which only `$` expansions, backticks and backslash escapes are special,
+/// up to the closing `"` when `double_quoted`, which is left for the caller, or else to
+/// the end of the input.
+fn handle_expandable_text(iter:&mut CharIter,double_quoted:bool)->Result<Vec<WordPart>,TokenizeError>{
+    let mut parts:Vec<WordPart> = vec![];
+    let mut literal = String::from("");
+    while let Some(char) = iter.peek(){
+        match char {
+            '"' if double_quoted => break,
+            '$' => {
+                push_literal(&mut parts, &mut literal);
+                parts.push(handle_dollar(iter)?);
+            },
+            '`' => {
+                push_literal(&mut parts, &mut literal);
+                parts.push(handle_backtick(iter, double_quoted)?);
+            },
+            char if is_invalid(char) => {
+                return Err(invalid_character(iter, char));
+            },
+            '\\' => {
+                iter.next();
+                match iter.peek() {
+                    // only these characters can be escaped inside double quotes
+                    Some(escaped @ ('$' | '`' | '\\')) => {
+                        push_literal(&mut parts, &mut literal);
+                        parts.push(WordPart::Escaped(escaped));
+                        iter.next();
+                    },
+                    Some('"') if double_quoted => {
+                        push_literal(&mut parts, &mut literal);
+                        parts.push(WordPart::Escaped('"'));
+                        iter.next();
+                    },
+                    Some('\n') => {
+                        iter.next();
+                    },
+                    _ => literal.push('\\')
+                }
+            },
+            _ => {
+                literal.push(char);
+                iter.next();
+            }
+        }
+    }
+    push_literal(&mut parts, &mut literal);
+    Ok(parts)
+}
+
+/// Pushes a part, merging it into the previous one when both are unquoted literals.
+fn push_part(parts:&mut Vec<WordPart>,part:WordPart){
+    if let (Some(WordPart::Literal(previous)),WordPart::Literal(next)) = (parts.last_mut(),&part){
+        previous.push_str(next);
+        return;
+    }
+    parts.push(part);
+}
+
+fn push_literal(parts:&mut Vec<WordPart>,literal:&mut String){
+    if !literal.is_empty(){
+        parts.push(WordPart::Literal(std::mem::take(literal)));
+    }
+}
+
+/// Handles a `$` that starts a variable or command substitution. A lone `$` stays literal.
+fn handle_dollar(iter:&mut CharIter)->Result<WordPart,TokenizeError>{
+    let span = iter.start_span();
+    iter.next();
+    match iter.peek() {
+        Some('(') => {
+            iter.next();
+            if iter.peek() == Some('('){
+                // `$((` is arithmetic unless its parentheses only close one at a time
+                let mut ahead = iter.clone();
+                ahead.next();
+                if let Some(expression) = handle_arithmetic(&mut ahead){
+                    *iter = ahead;
+                    return Ok(WordPart::Arithmetic(expression));
                 }
-        iterator.next();
-    
+            }
+            match handle_command_substitution(iter) {
+                Some(command) => Ok(WordPart::CommandSubstitution(command)),
+                None => Err(TokenizeError::IncompleteInput { span: iter.end_span(span) })
+            }
+        },
+        Some('{') => {
+            iter.next();
+            handle_braced_parameter(iter, span)
+        },
+        Some(char) if char.is_ascii_alphabetic() || char == '_' => {
+            Ok(WordPart::Variable(handle_variable(iter)))
+        },
+        Some(digit @ '1'..='9') => {
+            iter.next();
+            Ok(WordPart::Positional(digit as usize - '0' as usize))
+        },
+        Some(char) if is_special_parameter(char) => {
+            iter.next();
+            Ok(WordPart::SpecialParameter(char))
+        },
+        _ => Ok(WordPart::Literal(String::from("$")))
     }
+}
+
+fn is_special_parameter(char:char)->bool{
+    matches!(char,'?' | '#' | '@' | '*' | '$' | '!' | '-' | '0')
+}
 
+/// Reads `${...}` after its opening brace up to the matching `}`. The parameter at the
+/// front is checked here, the operator after it is left to the parser.
+fn handle_braced_parameter(iter:&mut CharIter,span:Span)->Result<WordPart,TokenizeError>{
+    let mut text = String::from("");
+    if !copy_braced_parameter(iter, &mut text){
+        return Err(TokenizeError::IncompleteInput { span: iter.end_span(span) });
+    }
+    text.pop();
+    if !is_valid_braced_parameter(&text){
+        return Err(TokenizeError::BadSubstitution { text: format!("${{{text}}}"), span: iter.end_span(span) });
+    }
+    Ok(WordPart::BracedParameter(text))
+}
 
-    output_tokens
+// The copy_* functions below copy the source of a construct whose opening has already been
+// consumed, up to and including its closing character, so it can be tokenized again later.
+// They return false when input ends first.
+
+fn copy_single_quoted(iter:&mut CharIter,text:&mut String)->bool{
+    for char in iter.by_ref(){
+        text.push(char);
+        if char == '\''{
+            return true;
+        }
+    }
+    false
+}
+
+fn copy_double_quoted(iter:&mut CharIter,text:&mut String)->bool{
+    while let Some(char) = iter.next(){
+        text.push(char);
+        let closed = match char {
+            '"' => return true,
+            '\\' => {
+                text.extend(iter.next());
+                true
+            },
+            '$' | '`' => copy_substitution(iter, char, text),
+            _ => true
+        };
+        if !closed{
+            return false;
+        }
+    }
+    false
+}
+
+/// Copies whatever a `$` or backtick just pushed to `text` starts. A `$` not followed by
+/// `(` or `{` starts nothing and is left alone.
+fn copy_substitution(iter:&mut CharIter,char:char,text:&mut String)->bool{
+    if char == '`'{
+        return copy_backtick(iter, text);
+    }
+    match iter.next_if(|next| *next == '(' || *next == '{') {
+        Some('(') => {
+            text.push('(');
+            copy_command_substitution(iter, text)
+        },
+        Some(_) => {
+            text.push('{');
+            copy_braced_parameter(iter, text)
+        },
+        None => true
+    }
+}
+
+fn copy_backtick(iter:&mut CharIter,text:&mut String)->bool{
+    while let Some(char) = iter.next(){
+        text.push(char);
+        match char {
+            '`' => return true,
+            '\\' => text.extend(iter.next()),
+            _ => {}
+        }
+    }
+    false
+}
+
+fn copy_braced_parameter(iter:&mut CharIter,text:&mut String)->bool{
+    while let Some(char) = iter.next(){
+        text.push(char);
+        let closed = match char {
+            '}' => return true,
+            '\\' => {
+                text.extend(iter.next());
+                true
+            },
+            '\'' => copy_single_quoted(iter, text),
+            '"' => copy_double_quoted(iter, text),
+            '$' | '`' => copy_substitution(iter, char, text),
+            _ => true
+        };
+        if !closed{
+            return false;
+        }
+    }
+    false
+}
+
+/// Copies the body of `$(...)` up to its matching `)`. Quotes, comments and nested
+/// substitutions are skipped over, and `case ... esac` is tracked so the `)` after a case
+/// pattern does not end the substitution.
+fn copy_command_substitution(iter:&mut CharIter,text:&mut String)->bool{
+    let mut depth = 0usize;
+    let mut case_depth = 0;
+    // unquoted word being read, used to spot comments and case/esac
+    let mut word = String::from("");
+    let mut command_start = true;
+    while let Some(char) = iter.next(){
+        if is_word_delimiter(char){
+            finish_word(&mut word, &mut case_depth, &mut command_start);
+        }
+        match char {
+            '#' if word.is_empty() => {
+                text.push(char);
+                while let Some(comment_char) = iter.next_if(|next| *next != '\n'){
+                    text.push(comment_char);
+                }
+                continue;
+            },
+            ')' if depth == 0 && case_depth == 0 => {
+                text.push(char);
+                return true;
+            },
+            ')' => {
+                // without open parentheses this closes a case pattern
+                depth = depth.saturating_sub(1);
+                command_start = true;
+            },
+            '(' => {
+                depth += 1;
+                command_start = true;
+            },
+            ';' | '&' | '|' | '\n' => command_start = true,
+            char if is_word_delimiter(char) => {},
+            _ => word.push(char)
+        }
+        text.push(char);
+        let closed = match char {
+            '\\' => {
+                text.extend(iter.next());
+                true
+            },
+            '\'' => copy_single_quoted(iter, text),
+            '"' => copy_double_quoted(iter, text),
+            '$' | '`' => copy_substitution(iter, char, text),
+            _ => true
+        };
+        if !closed{
+            return false;
+        }
+    }
+    false
 }
 
-fn handle_variable(iter: &mut std::iter::Peekable<std::str::Chars>)->String{
+/// Ends the word being read inside `$(...)`, counting `case` when it starts a command and
+/// any `esac` closing one.
+fn finish_word(word:&mut String,case_depth:&mut usize,command_start:&mut bool){
+    if word.is_empty(){
+        return;
+    }
+    if word == "case" && *command_start{
+        *case_depth += 1;
+    }
+    else if word == "esac" && *case_depth > 0{
+        *case_depth -= 1;
+    }
+    // words after these still start a command
+    *command_start = matches!(word.as_str(),"then" | "do" | "else" | "elif" | "if" | "while" | "until" | "{" | "!" | "time");
+    word.clear();
+}
+
+/// Checks the parameter at the front of a `${...}` body: a name, a position or a special
+/// parameter, optionally after `#` (length) or `!` (indirection), followed by nothing or
+/// an expansion operator.
+fn is_valid_braced_parameter(text:&str)->bool{
+    // `${#}` and `${!}` are the special parameters themselves
+    if text == "#" || text == "!"{
+        return true;
+    }
+    let (prefix,rest) = match text.chars().next() {
+        Some(prefix @ ('#' | '!')) => (Some(prefix),&text[1..]),
+        _ => (None,text)
+    };
+    let parameter_len = match rest.chars().next() {
+        Some(char) if char.is_ascii_alphabetic() || char == '_' => {
+            rest.find(|char:char| !(char.is_ascii_alphanumeric() || char == '_')).unwrap_or(rest.len())
+        },
+        Some(char) if char.is_ascii_digit() => {
+            rest.find(|char:char| !char.is_ascii_digit()).unwrap_or(rest.len())
+        },
+        Some(char) if is_special_parameter(char) => 1,
+        _ => return false
+    };
+    let operator = &rest[parameter_len..];
+    match prefix {
+        // length takes nothing after the parameter
+        Some('#') => operator.is_empty(),
+        _ => operator.is_empty() || operator.starts_with(['-','=','?','+','#','%','/',':','^',',','@'])
+    }
+}
+
+fn handle_variable(iter:&mut CharIter)->String{
     let mut var_name = String::from("");
     while let Some(char) = iter.peek(){
         match char{
-            char if char.is_alphanumeric() => {
-                var_name+=&char.to_string();
-            },
-            ' ' => {
-                println!("{}",char);
-                return var_name;
+            char if char.is_ascii_alphanumeric() || char == '_' => {
+                var_name.push(char);
             },
             _ => {
-                eprintln!("Invalid character found!")
+                return var_name;
             }
 
         }
@@ -115,14 +1049,84 @@ fn handle_variable(iter: &mut std::iter::Peekable<std::str::Chars>)->String{
     var_name
 }
 
-fn handle_unreserved_chars(iter: &mut std::iter::Peekable<std::str::Chars>)->String{
+/// Reads the body of `$(...)` up to its matching parenthesis. None when input ends first.
+fn handle_command_substitution(iter:&mut CharIter)->Option<String>{
+    let mut command = String::from("");
+    if !copy_command_substitution(iter, &mut command){
+        return None;
+    }
+    command.pop();
+    Some(command)
+}
+
+/// Reads the expression of `$((...))` up to the closing `))`. None when the parentheses
+/// don't end in `))`, as in `$( (cd x); ls )`, or when input ends first.
+fn handle_arithmetic(iter:&mut CharIter)->Option<String>{
+    let mut expression = String::from("");
+    let mut depth = 0usize;
+    while let Some(char) = iter.next(){
+        match char {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            ')' => {
+                return iter.next_if_eq(&')').map(|_| expression);
+            },
+            '$' | '`' => {
+                expression.push(char);
+                if !copy_substitution(iter, char, &mut expression){
+                    return None;
+                }
+                continue;
+            },
+            _ => {}
+        }
+        expression.push(char);
+    }
+    None
+}
+
+/// Reads a legacy `` `...` `` substitution. Inside backticks a backslash only escapes `$`,
+/// `` ` ``, `\` and, within double quotes, `"`. Those escapes are removed to get the inner command.
+fn handle_backtick(iter:&mut CharIter,in_double_quotes:bool)->Result<WordPart,TokenizeError>{
+    let span = iter.start_span();
+    iter.next();
+    let mut command = String::from("");
+    while let Some(char) = iter.next(){
+        match char {
+            '`' => return Ok(WordPart::CommandSubstitution(command)),
+            '\\' => {
+                match iter.peek() {
+                    Some(escaped @ ('$' | '`' | '\\')) => {
+                        command.push(escaped);
+                        iter.next();
+                    },
+                    Some('"') if in_double_quotes => {
+                        command.push('"');
+                        iter.next();
+                    },
+                    _ => command.push(char)
+                }
+            },
+            _ => command.push(char)
+        }
+    }
+    Err(TokenizeError::IncompleteInput { span: iter.end_span(span) })
+}
+
+fn handle_unreserved_chars(iter:&mut CharIter)->String{
     let mut word = String::from("");
-        while let Some(char) = iter.peek(){
+    while let Some(char) = iter.peek(){
         match char{
-            char if char.is_alphanumeric() => {
-                word.push(*char);
+            '\'' | '"' | '$' | '`' | '\\' | '*' | '?' | '[' | ']' | '~' => {
+                return word;
             },
-            
+            '+' | '@' | '!' if at_extglob_group(iter) => {
+                return word;
+            },
+            char if !is_word_delimiter(char) && !is_invalid(char) => {
+                word.push(char);
+            },
+
             _ => {
                 return word;
             }
@@ -133,23 +1137,28 @@ fn handle_unreserved_chars(iter: &mut std::iter::Peekable<std::str::Chars>)->Str
     word
 }
 
-fn handle_comment_line(iter: &mut std::iter::Peekable<std::str::Chars>)->String{
+/// Reads a comment from its `#` to the end of the line. The newline is left for the caller.
+fn handle_comment_line(iter:&mut CharIter)->String{
+    iter.next();
     let mut commented_line = String::from("");
-    // while let Some(char) = iter.peek(){
-
-    // }
-
+    while let Some(char) = iter.next_if(|char| *char != '\n'){
+        commented_line.push(char);
+    }
     commented_line
 }
 
 #[cfg(test)]
-
 mod tests{
     use super::*;
+    use crate::error::render_diagnostic;
+
+    fn tokenize_kinds(input:&str)->Vec<ShellTokens>{
+        tokenize_input_intermediate(input).unwrap().into_iter().map(|token| token.kind).collect()
+    }
 
     #[test]
     fn test_basic_tokenization() {
-        let tokens = tokenize_input_intermediate("echo hello $abc (d) | grep a");
+        let tokens = tokenize_kinds("echo hello $abc (d) | grep a");
         assert_eq!(
             tokens,
             vec![
@@ -157,18 +1166,483 @@ mod tests{
                 ShellTokens::Whitespace,
                 ShellTokens::Word("hello".into()),
                 ShellTokens::Whitespace,
-                ShellTokens::Variable(String::from("abc")),
+                ShellTokens::Word(Word{parts:vec![WordPart::Variable(String::from("abc"))]}),
                 ShellTokens::Whitespace,
                 ShellTokens::ParenthesesOpen,
-                ShellTokens::Word(String::from("d")),
+                ShellTokens::Word("d".into()),
                 ShellTokens::ParenthesesClose,
                 ShellTokens::Whitespace,
                 ShellTokens::Pipe,
                 ShellTokens::Whitespace,
-                ShellTokens::Word(String::from("grep")),
+                ShellTokens::Word("grep".into()),
+                ShellTokens::Whitespace,
+                ShellTokens::Word("a".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_single_quotes_are_one_literal_word() {
+        let tokens = tokenize_kinds("grep 'hello $world | x' file");
+        assert_eq!(
+            tokens,
+            vec![
+                ShellTokens::Word("grep".into()),
+                ShellTokens::Whitespace,
+                ShellTokens::Word(Word{parts:vec![WordPart::SingleQuoted(String::from("hello $world | x"))]}),
+                ShellTokens::Whitespace,
+                ShellTokens::Word("file".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_double_quotes_keep_expansions_as_parts() {
+        let tokens = tokenize_kinds("echo \"$HOME/x y $(pwd) \\$ \\n\"");
+        assert_eq!(
+            tokens[2],
+            ShellTokens::Word(Word{parts:vec![WordPart::DoubleQuoted(vec![
+                WordPart::Variable(String::from("HOME")),
+                WordPart::Literal(String::from("/x y ")),
+                WordPart::CommandSubstitution(String::from("pwd")),
+                WordPart::Literal(String::from(" ")),
+                WordPart::Escaped('$'),
+                WordPart::Literal(String::from(" \\n")),
+            ])]})
+        );
+    }
+
+    #[test]
+    fn test_adjacent_quotes_join_into_one_word() {
+        let tokens = tokenize_kinds("a'b'\"c\"");
+        assert_eq!(
+            tokens,
+            vec![ShellTokens::Word(Word{parts:vec![
+                WordPart::Literal(String::from("a")),
+                WordPart::SingleQuoted(String::from("b")),
+                WordPart::DoubleQuoted(vec![WordPart::Literal(String::from("c"))]),
+            ]})]
+        );
+    }
+
+    #[test]
+    fn test_unterminated_quote_is_an_error() {
+        assert_eq!(
+            tokenize_input_intermediate("echo 'abc"),
+            Err(TokenizeError::UnterminatedQuote { quote: '\'', span: Span { start: 5, end: 9, line: 1, column: 6 } })
+        );
+        assert_eq!(
+            tokenize_input_intermediate("echo \"abc"),
+            Err(TokenizeError::UnterminatedQuote { quote: '"', span: Span { start: 5, end: 9, line: 1, column: 6 } })
+        );
+    }
+
+    #[test]
+    fn test_backslash_makes_next_char_literal() {
+        let tokens = tokenize_kinds("a\\ b\\|");
+        assert_eq!(
+            tokens,
+            vec![ShellTokens::Word(Word{parts:vec![
+                WordPart::Literal(String::from("a")),
+                WordPart::Escaped(' '),
+                WordPart::Literal(String::from("b")),
+                WordPart::Escaped('|'),
+            ]})]
+        );
+    }
+
+    #[test]
+    fn test_line_continuation_joins_lines() {
+        let tokens = tokenize_kinds("ec\\\nho \\\n hi\n");
+        assert_eq!(
+            tokens,
+            vec![
+                ShellTokens::Word("echo".into()),
+                ShellTokens::Whitespace,
                 ShellTokens::Whitespace,
-                ShellTokens::Word(String::from("a")),
+                ShellTokens::Word("hi".into()),
+                ShellTokens::Newline,
             ]
         );
+        let tokens = tokenize_kinds("\"a\\\nb\\z\"");
+        assert_eq!(
+            tokens,
+            vec![ShellTokens::Word(Word{parts:vec![WordPart::DoubleQuoted(vec![
+                WordPart::Literal(String::from("ab\\z")),
+            ])]})]
+        );
+    }
+
+    #[test]
+    fn test_trailing_continuation_needs_more_input() {
+        assert!(needs_more_input("echo a \\\n"));
+        assert!(needs_more_input("echo a\\\n"));
+        assert!(!needs_more_input("echo a\n"));
+        assert!(!needs_more_input("echo a \\\\\n"));
+        assert!(!needs_more_input("echo 'a \\\n'"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_operators_use_longest_match() {
+        let tokens = tokenize_kinds("a&&b||c;d&e|&f|g>>h<<<i<<j&>k&>>l>|m<>n\nj");
+        let operators:Vec<&ShellTokens> = tokens.iter()
+            .filter(|token| !matches!(token,ShellTokens::Word(_) | ShellTokens::HereDocument(_)))
+            .collect();
+        assert_eq!(
+            operators,
+            vec![
+                &ShellTokens::And,
+                &ShellTokens::Or,
+                &ShellTokens::Semicolon,
+                &ShellTokens::Background,
+                &ShellTokens::PipeWithError,
+                &ShellTokens::Pipe,
+                &ShellTokens::AppendOutput(None),
+                &ShellTokens::HereString(None),
+                &ShellTokens::HereDoc(None),
+                &ShellTokens::RedirectOutputAndError,
+                &ShellTokens::AppendOutputAndError,
+                &ShellTokens::Clobber(None),
+                &ShellTokens::ReadWrite(None),
+                &ShellTokens::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_redirections_take_leading_fd() {
+        let tokens = tokenize_kinds("cmd 2>err 3<&0 12 >out");
+        assert_eq!(
+            tokens,
+            vec![
+                ShellTokens::Word("cmd".into()),
+                ShellTokens::Whitespace,
+                ShellTokens::RedirectOutput(Some(2)),
+                ShellTokens::Word("err".into()),
+                ShellTokens::Whitespace,
+                ShellTokens::DuplicateInput(Some(3)),
+                ShellTokens::Word("0".into()),
+                ShellTokens::Whitespace,
+                ShellTokens::Word("12".into()),
+                ShellTokens::Whitespace,
+                ShellTokens::RedirectOutput(None),
+                ShellTokens::Word("out".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_paths_and_flags_are_words() {
+        let tokens = tokenize_kinds("ls -la /usr/bin\tcat file.txt a=b:c foo#bar");
+        let words:Vec<&ShellTokens> = tokens.iter()
+            .filter(|token| **token != ShellTokens::Whitespace)
+            .collect();
+        assert_eq!(
+            words,
+            vec![
+                &ShellTokens::Word("ls".into()),
+                &ShellTokens::Word("-la".into()),
+                &ShellTokens::Word("/usr/bin".into()),
+                &ShellTokens::Word("cat".into()),
+                &ShellTokens::Word("file.txt".into()),
+                &ShellTokens::Word("a=b:c".into()),
+                &ShellTokens::Word("foo#bar".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_glob_and_tilde_markers() {
+        let tokens = tokenize_kinds("~/src/*.r[sx]'*'");
+        assert_eq!(
+            tokens,
+            vec![ShellTokens::Word(Word{parts:vec![
+                WordPart::Tilde,
+                WordPart::Literal(String::from("/src/")),
+                WordPart::GlobChar('*'),
+                WordPart::Literal(String::from(".r")),
+                WordPart::GlobChar('['),
+                WordPart::Literal(String::from("sx")),
+                WordPart::GlobChar(']'),
+                WordPart::SingleQuoted(String::from("*")),
+            ]})]
+        );
+    }
+
+    #[test]
+    fn test_extglob_groups_stay_in_the_word() {
+        let tokens = tokenize_kinds("a@(x|y)!(z) (b)");
+        assert_eq!(
+            tokens,
+            vec![
+                ShellTokens::Word(Word{parts:vec![
+                    WordPart::Literal(String::from("a")),
+                    WordPart::GlobChar('@'),
+                    WordPart::GlobChar('('),
+                    WordPart::Literal(String::from("x")),
+                    WordPart::GlobChar('|'),
+                    WordPart::Literal(String::from("y")),
+                    WordPart::GlobChar(')'),
+                    WordPart::GlobChar('!'),
+                    WordPart::GlobChar('('),
+                    WordPart::Literal(String::from("z")),
+                    WordPart::GlobChar(')'),
+                ]}),
+                ShellTokens::Whitespace,
+                ShellTokens::ParenthesesOpen,
+                ShellTokens::Word("b".into()),
+                ShellTokens::ParenthesesClose,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokens_carry_spans() {
+        let tokens = tokenize_input_intermediate("echo 'a b'\n  ls >>out").unwrap();
+        let spans:Vec<Span> = tokens.iter()
+            .filter(|token| token.kind != ShellTokens::Whitespace)
+            .map(|token| token.span)
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                Span { start: 0, end: 4, line: 1, column: 1 },
+                Span { start: 5, end: 10, line: 1, column: 6 },
+                Span { start: 10, end: 11, line: 1, column: 11 },
+                Span { start: 13, end: 15, line: 2, column: 3 },
+                Span { start: 16, end: 18, line: 2, column: 6 },
+                Span { start: 18, end: 21, line: 2, column: 8 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_renders_source_line_with_caret() {
+        let source = "echo ok\necho 'abc\n";
+        let err = tokenize_input_intermediate(source).unwrap_err();
+        assert_eq!(
+            render_diagnostic("deploy.sh", source, err.span(), &err.to_string()),
+            "error: unterminated single quote\n --> deploy.sh:2:6\n  |\n2 | echo 'abc\n  |      ^^^^"
+        );
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        assert_eq!(
+            tokenize_input_intermediate("echo ${a b}"),
+            Err(TokenizeError::BadSubstitution { text: String::from("${a b}"), span: Span { start: 5, end: 11, line: 1, column: 6 } })
+        );
+        assert_eq!(
+            tokenize_input_intermediate("echo a\0b"),
+            Err(TokenizeError::InvalidCharacter { char: '\0', span: Span { start: 6, end: 7, line: 1, column: 7 } })
+        );
+        assert_eq!(
+            tokenize_input_intermediate("echo $(ls"),
+            Err(TokenizeError::IncompleteInput { span: Span { start: 5, end: 9, line: 1, column: 6 } })
+        );
+    }
+
+    #[test]
+    fn test_open_quotes_and_substitutions_need_more_input() {
+        assert!(needs_more_input("echo 'abc\n"));
+        assert!(needs_more_input("echo \"$(ls\n"));
+        assert!(!needs_more_input("echo ${a b}\n"));
+    }
+
+    #[test]
+    fn test_comments_run_to_end_of_line() {
+        assert_eq!(
+            tokenize_kinds("echo a#b # it's a comment\nls"),
+            vec![
+                ShellTokens::Word("echo".into()),
+                ShellTokens::Whitespace,
+                ShellTokens::Word("a#b".into()),
+                ShellTokens::Whitespace,
+                ShellTokens::Newline,
+                ShellTokens::Word("ls".into()),
+            ]
+        );
+        assert!(!needs_more_input("echo a # don't \\\n"));
+    }
+
+    #[test]
+    fn test_comments_can_be_kept() {
+        let options = TokenizerOptions { keep_comments: true };
+        let tokens = tokenize_with_options("ls;# list files\n", &options).unwrap();
+        assert_eq!(
+            tokens[2],
+            Token { kind: ShellTokens::Comment(String::from(" list files")), span: Span { start: 3, end: 15, line: 1, column: 4 } }
+        );
+        assert_eq!(tokens[3].kind, ShellTokens::Newline);
+    }
+
+    fn reserved_words(input:&str)->Vec<&'static str>{
+        tokenize_kinds(input).into_iter()
+            .filter_map(|kind| match kind {
+                ShellTokens::ReservedWord(reserved) => Some(reserved.as_str()),
+                _ => None
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reserved_words_only_in_command_position() {
+        assert_eq!(reserved_words("echo if then fi"), Vec::<&str>::new());
+        assert_eq!(reserved_words("if true; then echo fi; else ! false; fi"), vec!["if","then","else","!","fi"]);
+        assert_eq!(reserved_words("\"if\" a; 'fi'"), Vec::<&str>::new());
+        assert_eq!(reserved_words("while read line > if; do { echo; }; done"), vec!["while","do","{","}","done"]);
+    }
+
+    #[test]
+    fn test_reserved_words_after_for_and_case() {
+        assert_eq!(reserved_words("for in in do done; do echo in; done"), vec!["for","in","do","done"]);
+        assert_eq!(reserved_words("for x do echo; done"), vec!["for","do","done"]);
+        assert_eq!(reserved_words("case in in\nin) echo esac;; esac"), vec!["case","in","in","esac"]);
+        assert_eq!(reserved_words("function if { time ls; }"), vec!["function","{","time","}"]);
+    }
+
+    #[test]
+    fn test_double_brackets_hide_reserved_words() {
+        assert_eq!(reserved_words("[[ if && ! ]] && [[ a ]]"), vec!["[[","]]","[[","]]"]);
+    }
+
+    #[test]
+    fn test_parameter_expansion_parts() {
+        assert_eq!(
+            tokenize_kinds("$ab${a}b$10$?$#\"$@\"$*$$$!$-$0$"),
+            vec![ShellTokens::Word(Word{parts:vec![
+                WordPart::Variable(String::from("ab")),
+                WordPart::BracedParameter(String::from("a")),
+                WordPart::Literal(String::from("b")),
+                WordPart::Positional(1),
+                WordPart::Literal(String::from("0")),
+                WordPart::SpecialParameter('?'),
+                WordPart::SpecialParameter('#'),
+                WordPart::DoubleQuoted(vec![WordPart::SpecialParameter('@')]),
+                WordPart::SpecialParameter('*'),
+                WordPart::SpecialParameter('$'),
+                WordPart::SpecialParameter('!'),
+                WordPart::SpecialParameter('-'),
+                WordPart::SpecialParameter('0'),
+                WordPart::Literal(String::from("$")),
+            ]})]
+        );
+    }
+
+    #[test]
+    fn test_braced_parameters_keep_source_text() {
+        let braced = |input:&str| match tokenize_kinds(input).remove(0) {
+            ShellTokens::Word(Word{parts}) => parts,
+            other => panic!("expected a word, got {other:?}")
+        };
+        assert_eq!(braced("${name:-a b}"), vec![WordPart::BracedParameter(String::from("name:-a b"))]);
+        assert_eq!(braced("${x:-'}'}"), vec![WordPart::BracedParameter(String::from("x:-'}'"))]);
+        assert_eq!(braced("${x:-${y:-\\}}}"), vec![WordPart::BracedParameter(String::from("x:-${y:-\\}}"))]);
+        assert_eq!(braced("${x#$(echo })}"), vec![WordPart::BracedParameter(String::from("x#$(echo })"))]);
+        assert_eq!(braced("${#var}${12}${!ref}${@:2}${#}"), vec![
+            WordPart::BracedParameter(String::from("#var")),
+            WordPart::BracedParameter(String::from("12")),
+            WordPart::BracedParameter(String::from("!ref")),
+            WordPart::BracedParameter(String::from("@:2")),
+            WordPart::BracedParameter(String::from("#")),
+        ]);
+    }
+
+    #[test]
+    fn test_bad_braced_parameters() {
+        for input in ["${}", "${a b}", "${1a}", "${#a:-b}", "${-x-}"]{
+            assert!(
+                matches!(tokenize_input_intermediate(input),Err(TokenizeError::BadSubstitution { .. })),
+                "{input} should be a bad substitution"
+            );
+        }
+        assert!(needs_more_input("echo ${a:-\n"));
+    }
+
+    fn substitutions(input:&str)->Vec<String>{
+        fn collect(parts:&[WordPart],found:&mut Vec<String>){
+            for part in parts{
+                match part {
+                    WordPart::CommandSubstitution(command) => found.push(command.clone()),
+                    WordPart::DoubleQuoted(inner) => collect(inner, found),
+                    _ => {}
+                }
+            }
+        }
+        let mut found = vec![];
+        for kind in tokenize_kinds(input){
+            if let ShellTokens::Word(word) = kind{
+                collect(&word.parts, &mut found);
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn test_command_substitution_nesting_and_quotes() {
+        assert_eq!(substitutions("echo $(git rev-parse HEAD)"), vec!["git rev-parse HEAD"]);
+        assert_eq!(substitutions("$(echo $(echo (a)) ')' \")\" \\))"), vec!["echo $(echo (a)) ')' \")\" \\)"]);
+        assert_eq!(substitutions("\"$(echo \")\")\""), vec!["echo \")\""]);
+        assert_eq!(substitutions("$(echo # not the end )\nls)"), vec!["echo # not the end )\nls"]);
+        assert_eq!(substitutions("$(case x in a) echo case;; esac)"), vec!["case x in a) echo case;; esac"]);
+        assert_eq!(substitutions("$(echo case)"), vec!["echo case"]);
+        assert_eq!(substitutions("$(echo ${x:-)})"), vec!["echo ${x:-)}"]);
+    }
+
+    #[test]
+    fn test_backticks_unescape_inner_command() {
+        assert_eq!(substitutions("echo `date`"), vec!["date"]);
+        assert_eq!(substitutions("`echo \\`pwd\\` \\$HOME \\n`"), vec!["echo `pwd` $HOME \\n"]);
+        assert_eq!(substitutions("\"`echo \\\"hi\\\"`\""), vec!["echo \"hi\""]);
+        assert!(needs_more_input("echo `date\n"));
+        assert!(needs_more_input("echo $(echo ')'\n"));
+    }
+
+    #[test]
+    fn test_arithmetic_expansion_parts() {
+        assert_eq!(
+            tokenize_kinds("$((1 + (2 * $x) + $(echo 3)))\"$((i++))\""),
+            vec![ShellTokens::Word(Word{parts:vec![
+                WordPart::Arithmetic(String::from("1 + (2 * $x) + $(echo 3)")),
+                WordPart::DoubleQuoted(vec![WordPart::Arithmetic(String::from("i++"))]),
+            ]})]
+        );
+        assert_eq!(substitutions("$( (cd /tmp); ls )"), vec![" (cd /tmp); ls "]);
+        assert_eq!(substitutions("$((cd /tmp); ls)"), vec!["(cd /tmp); ls"]);
+        assert!(needs_more_input("echo $((1 +\n"));
+    }
+
+    #[test]
+    fn test_case_terminators() {
+        assert_eq!(
+            tokenize_kinds(";;;&;;&;"),
+            vec![ShellTokens::DoubleSemicolon, ShellTokens::SemicolonAnd, ShellTokens::DoubleSemicolonAnd, ShellTokens::Semicolon]
+        );
+    }
+
+    #[test]
+    fn test_here_document_bodies() {
+        let tokens = tokenize_kinds("cat <<EOF; cat <<-'END'\nhi $x\nEOF\n\tbye\n\tEND\nls\n");
+        assert_eq!(
+            tokens,
+            vec![
+                ShellTokens::Word("cat".into()),
+                ShellTokens::Whitespace,
+                ShellTokens::HereDoc(None),
+                ShellTokens::HereDocument(HereDocument { body: String::from("hi $x\n"), quoted: false }),
+                ShellTokens::Semicolon,
+                ShellTokens::Whitespace,
+                ShellTokens::Word("cat".into()),
+                ShellTokens::Whitespace,
+                ShellTokens::HereDocStripTabs(None),
+                ShellTokens::HereDocument(HereDocument { body: String::from("bye\n"), quoted: true }),
+                ShellTokens::Newline,
+                ShellTokens::Word("ls".into()),
+                ShellTokens::Newline,
+            ]
+        );
+        assert!(needs_more_input("cat <<EOF\nhi\n"));
+        assert!(needs_more_input("cat <<EOF"));
+        assert!(!needs_more_input("cat <<EOF\nhi\nEOF"));
+    }
+}
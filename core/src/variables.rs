@@ -0,0 +1,519 @@
+//! Shell variables, their attributes and the builtins that manage them.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io::Write;
+
+use nix::errno::Errno;
+use nix::unistd::read;
+
+use crate::arithmetic::{ArithmeticContext, evaluate};
+use crate::error::{ArithmeticError, VariableError};
+use crate::parser::is_name;
+
+/// Field separators used when `IFS` is unset.
+pub(crate) const DEFAULT_IFS:&str = " \t\n";
+
+/// A variable's value along with its attributes.
+#[derive(Debug,Clone,Default,PartialEq,Eq)]
+pub struct Variable{
+    /// None for a variable that has attributes but was never given a value, as after
+    /// `export NAME`.
+    pub value:Option<String>,
+    /// Passed to the environment of commands the shell runs.
+    pub exported:bool,
+    /// Can be neither assigned nor unset.
+    pub readonly:bool,
+    /// Assigned values are evaluated as arithmetic expressions.
+    pub integer:bool
+}
+
+/// The shell's variable table.
+#[derive(Debug,Clone,Default)]
+pub struct Variables{
+    table:HashMap<String,Variable>,
+    /// One list per running function of the variables its `local` hid, along with what
+    /// they held, innermost last.
+    scopes:Vec<Vec<(String,Option<Variable>)>>
+}
+
+impl Variables{
+    pub fn new()->Self{
+        Variables { table: HashMap::new(), scopes: vec![] }
+    }
+
+    /// Variables taken from the shell's own environment, all exported.
+    pub fn from_environment()->Self{
+        let mut variables = Variables::new();
+        for (name,value) in std::env::vars_os(){
+            let (Some(name),Some(value)) = (name.to_str(),value.to_str()) else {
+                continue;
+            };
+            if is_name(name){
+                variables.table.insert(name.to_string(),Variable { value: Some(value.to_string()), exported: true, ..Variable::default() });
+            }
+        }
+        variables
+    }
+
+    /// The value of a set variable.
+    pub fn get(&self,name:&str)->Option<&str>{
+        self.table.get(name)?.value.as_deref()
+    }
+
+    pub fn variable(&self,name:&str)->Option<&Variable>{
+        self.table.get(name)
+    }
+
+    /// Assigns `value`, evaluating it first when the variable has the integer attribute.
+    pub fn set(&mut self,name:&str,value:&str)->Result<(),VariableError>{
+        if !is_name(name){
+            return Err(VariableError::InvalidName { name: name.to_string() });
+        }
+        let (readonly,integer) = self.table.get(name).map_or((false,false),|variable| (variable.readonly,variable.integer));
+        if readonly{
+            return Err(VariableError::Readonly { name: name.to_string() });
+        }
+        let value = match integer {
+            true => evaluate(value,self)
+                .map_err(|source| VariableError::Arithmetic { name: name.to_string(), source })?
+                .to_string(),
+            false => value.to_string()
+        };
+        self.table.entry(name.to_string()).or_default().value = Some(value);
+        Ok(())
+    }
+
+    pub fn unset(&mut self,name:&str)->Result<(),VariableError>{
+        if self.table.get(name).is_some_and(|variable| variable.readonly){
+            return Err(VariableError::Readonly { name: name.to_string() });
+        }
+        self.table.remove(name);
+        Ok(())
+    }
+
+    /// Sets the attributes `update` turns on, creating the variable without a value when
+    /// it does not exist yet.
+    pub fn set_attributes(&mut self,name:&str,update:impl FnOnce(&mut Variable))->Result<(),VariableError>{
+        if !is_name(name){
+            return Err(VariableError::InvalidName { name: name.to_string() });
+        }
+        update(self.table.entry(name.to_string()).or_default());
+        Ok(())
+    }
+
+    /// Puts back variables as they were before temporary assignments, given as the names
+    /// along with what they held, None for variables that did not exist. Goes through
+    /// them last first, so a name assigned twice ends up as it was originally.
+    pub fn restore(&mut self,previous:Vec<(String,Option<Variable>)>){
+        for (name,variable) in previous.into_iter().rev(){
+            match variable {
+                Some(variable) => self.table.insert(name,variable),
+                None => self.table.remove(&name)
+            };
+        }
+    }
+
+    /// Starts the scope of a function call, for the variables it makes local.
+    pub fn push_scope(&mut self){
+        self.scopes.push(vec![]);
+    }
+
+    /// Ends the innermost function scope, putting back the variables its `local` hid.
+    pub fn pop_scope(&mut self){
+        if let Some(previous) = self.scopes.pop(){
+            self.restore(previous);
+        }
+    }
+
+    /// `NAME=value` strings of the exported variables that have a value, for the
+    /// environment of commands the shell runs.
+    pub fn environment(&self)->Vec<CString>{
+        self.table.iter()
+            .filter(|(_,variable)| variable.exported)
+            .filter_map(|(name,variable)| CString::new(format!("{name}={}",variable.value.as_ref()?)).ok())
+            .collect()
+    }
+
+    /// Names and variables sorted by name, for listing.
+    pub fn sorted(&self)->Vec<(&String,&Variable)>{
+        let mut variables:Vec<_> = self.table.iter().collect();
+        variables.sort_by_key(|(name,_)| *name);
+        variables
+    }
+}
+
+impl ArithmeticContext for Variables{
+    fn get_variable(&self,name:&str)->Option<String>{
+        self.get(name).map(str::to_string)
+    }
+
+    fn set_variable(&mut self,name:&str,value:i64)->Result<(),ArithmeticError>{
+        self.set(name,&value.to_string())
+            .map_err(|err| ArithmeticError::Assignment { name: name.to_string(), reason: err.to_string() })
+    }
+}
+
+/// Attribute flags accepted by `declare`, `export` and `readonly`.
+#[derive(Default)]
+struct AttributeFlags{
+    exported:bool,
+    readonly:bool,
+    integer:bool,
+    /// `-p`, list instead of setting.
+    print:bool
+}
+
+/// Splits leading `-x`-style options off `args`. `allowed` holds the letters the builtin
+/// takes.
+fn parse_flags<'a>(builtin:&str,args:&'a [String],allowed:&str)->Result<(AttributeFlags,&'a [String]),String>{
+    let mut flags = AttributeFlags::default();
+    let mut rest = args;
+    while let Some(arg) = rest.first() {
+        if arg == "--"{
+            rest = &rest[1..];
+            break;
+        }
+        let Some(letters) = arg.strip_prefix('-').filter(|letters| !letters.is_empty()) else {
+            break;
+        };
+        for letter in letters.chars(){
+            if !allowed.contains(letter){
+                return Err(format!("{builtin}: -{letter}: invalid option"));
+            }
+            match letter {
+                'x' => flags.exported = true,
+                'r' => flags.readonly = true,
+                'i' => flags.integer = true,
+                'p' => flags.print = true,
+                _ => {}
+            }
+        }
+        rest = &rest[1..];
+    }
+    Ok((flags,rest))
+}
+
+/// Writes variables the way `declare -p` does, so the output can be read back in.
+fn print_variables(variables:&Variables,filter:impl Fn(&str,&Variable)->bool)->i32{
+    let mut stdout = std::io::stdout();
+    for (name,variable) in variables.sorted(){
+        if !filter(name,variable){
+            continue;
+        }
+        let mut attributes = String::from("");
+        if variable.integer { attributes.push('i') }
+        if variable.readonly { attributes.push('r') }
+        if variable.exported { attributes.push('x') }
+        if attributes.is_empty() { attributes.push('-') }
+        let line = match &variable.value {
+            Some(value) => format!("declare -{attributes} {name}=\"{}\"",escape_double_quoted(value)),
+            None => format!("declare -{attributes} {name}")
+        };
+        if writeln!(stdout,"{line}").is_err(){
+            return 1;
+        }
+    }
+    0
+}
+
+fn escape_double_quoted(value:&str)->String{
+    let mut escaped = String::from("");
+    for char in value.chars(){
+        if matches!(char,'"' | '\\' | '$' | '`'){
+            escaped.push('\\');
+        }
+        escaped.push(char);
+    }
+    escaped
+}
+
+/// `declare`, `export` and `readonly`: give each `name[=value]` the flagged attributes,
+/// then the value. Returns 1 when any of them failed.
+fn declare_variables(builtin:&str,variables:&mut Variables,flags:&AttributeFlags,args:&[String])->i32{
+    let mut status = 0;
+    for arg in args{
+        let (name,value) = match arg.split_once('=') {
+            Some((name,value)) => (name,Some(value)),
+            None => (arg.as_str(),None)
+        };
+        if variables.variable(name).is_some_and(|variable| variable.readonly) && value.is_some(){
+            eprintln!("hsh: {builtin}: {}",VariableError::Readonly { name: name.to_string() });
+            status = 1;
+            continue;
+        }
+        // readonly is set after the value, so `readonly NAME=value` can assign it
+        let result = variables.set_attributes(name,|variable| {
+            variable.exported |= flags.exported;
+            variable.integer |= flags.integer;
+        })
+            .and_then(|()| match value {
+                Some(value) => variables.set(name,value),
+                None => Ok(())
+            })
+            .and_then(|()| variables.set_attributes(name,|variable| variable.readonly |= flags.readonly));
+        if let Err(err) = result{
+            eprintln!("hsh: {builtin}: {err}");
+            status = 1;
+        }
+    }
+    status
+}
+
+/// `export [-p] [name[=value]...]`
+pub fn export_builtin(variables:&mut Variables,args:&[String])->i32{
+    match parse_flags("export",args,"p") {
+        Ok((_,[])) => print_variables(variables,|_,variable| variable.exported),
+        Ok((_,names)) => declare_variables("export",variables,&AttributeFlags { exported: true, ..AttributeFlags::default() },names),
+        Err(message) => usage_error(&message)
+    }
+}
+
+/// `readonly [-p] [name[=value]...]`
+pub fn readonly_builtin(variables:&mut Variables,args:&[String])->i32{
+    match parse_flags("readonly",args,"p") {
+        Ok((_,[])) => print_variables(variables,|_,variable| variable.readonly),
+        Ok((_,names)) => declare_variables("readonly",variables,&AttributeFlags { readonly: true, ..AttributeFlags::default() },names),
+        Err(message) => usage_error(&message)
+    }
+}
+
+/// `declare [-irxp] [name[=value]...]`, also run as `typeset`.
+pub fn declare_builtin(variables:&mut Variables,args:&[String])->i32{
+    match parse_flags("declare",args,"irxp") {
+        Ok((flags,[])) => print_variables(variables,|_,variable| {
+            (!flags.exported || variable.exported) && (!flags.readonly || variable.readonly) && (!flags.integer || variable.integer)
+        }),
+        Ok((flags,names)) if flags.print => print_variables(variables,|name,_| names.iter().any(|listed| listed == name)),
+        Ok((flags,names)) => declare_variables("declare",variables,&flags,names),
+        Err(message) => usage_error(&message)
+    }
+}
+
+/// `local [-irx] [name[=value]...]`: variables that last until the running function
+/// returns. They hide any of the same name from it and the functions it calls, starting
+/// out unset unless given a value.
+pub fn local_builtin(variables:&mut Variables,args:&[String])->i32{
+    let (flags,names) = match parse_flags("local",args,"irxp") {
+        Ok(parsed) => parsed,
+        Err(message) => return usage_error(&message)
+    };
+    let Some(scope) = variables.scopes.last() else {
+        eprintln!("hsh: local: can only be used in a function");
+        return 1;
+    };
+    if names.is_empty() || flags.print{
+        let locals:Vec<String> = scope.iter().map(|(name,_)| name.clone()).collect();
+        return print_variables(variables,|name,_| locals.iter().any(|local| local == name));
+    }
+    let mut status = 0;
+    for arg in names{
+        let name = arg.split_once('=').map_or(arg.as_str(),|(name,_)| name);
+        if variables.variable(name).is_some_and(|variable| variable.readonly){
+            eprintln!("hsh: local: {}",VariableError::Readonly { name: name.to_string() });
+            status = 1;
+            continue;
+        }
+        // only the first `local` in a call saves what the name held outside it
+        if let Some(scope) = variables.scopes.last_mut()
+            && is_name(name) && !scope.iter().any(|(saved,_)| saved == name){
+            let previous = variables.table.remove(name);
+            scope.push((name.to_string(),previous));
+        }
+        if declare_variables("local",variables,&flags,std::slice::from_ref(arg)) != 0{
+            status = 1;
+        }
+    }
+    status
+}
+
+/// `unset [-v] name...`
+pub fn unset_builtin(variables:&mut Variables,args:&[String])->i32{
+    let names = match parse_flags("unset",args,"v") {
+        Ok((_,names)) => names,
+        Err(message) => return usage_error(&message)
+    };
+    let mut status = 0;
+    for name in names{
+        if let Err(err) = variables.unset(name){
+            eprintln!("hsh: unset: {err}");
+            status = 1;
+        }
+    }
+    status
+}
+
+/// `read [-r] [name...]`: reads a line of standard input into the named variables, or
+/// whole into `REPLY` without names. The line is split at the characters of `IFS` and the
+/// last name gets the rest of it. Unless `-r` is given a backslash keeps the character
+/// after it from being a separator, and one at the end of a line joins the next line on.
+/// Fails at the end of input.
+pub fn read_builtin(variables:&mut Variables,args:&[String])->i32{
+    let mut raw = false;
+    let mut names = args;
+    while let Some(letters) = names.first().and_then(|arg| arg.strip_prefix('-')).filter(|letters| !letters.is_empty()) {
+        names = &names[1..];
+        if letters == "-"{
+            break;
+        }
+        match letters.chars().find(|letter| *letter != 'r') {
+            Some(letter) => return usage_error(&format!("read: -{letter}: invalid option")),
+            None => raw = true
+        }
+    }
+    let (line,at_end) = match read_line(raw) {
+        Ok(read) => read,
+        Err(errno) => {
+            eprintln!("hsh: read: {}",errno.desc());
+            return 1;
+        }
+    };
+    let separators = variables.get("IFS").unwrap_or(DEFAULT_IFS).to_string();
+    let values = match names {
+        [] => vec![split_line(&line,raw,"",1).remove(0)],
+        names => split_line(&line,raw,&separators,names.len())
+    };
+    let names:Vec<&str> = match names {
+        [] => vec!["REPLY"],
+        names => names.iter().map(String::as_str).collect()
+    };
+    let mut status = if at_end { 1 } else { 0 };
+    for (name,value) in names.into_iter().zip(values){
+        if let Err(err) = variables.set(name,&value){
+            eprintln!("hsh: read: {err}");
+            status = 1;
+        }
+    }
+    status
+}
+
+/// One line of standard input without its newline, read a byte at a time so nothing after
+/// it is taken away from the commands that read next. Also tells whether input ended
+/// before a newline.
+fn read_line(raw:bool)->Result<(String,bool),Errno>{
+    let stdin = std::io::stdin();
+    let mut line = vec![];
+    let mut escaped = false;
+    let mut byte = [0u8];
+    loop {
+        match read(&stdin,&mut byte) {
+            Ok(0) => return Ok((String::from_utf8_lossy(&line).into_owned(),true)),
+            Ok(_) => {},
+            Err(Errno::EINTR) => continue,
+            Err(errno) => return Err(errno)
+        }
+        match byte[0] {
+            b'\n' if escaped => {
+                line.pop();
+                escaped = false;
+            },
+            b'\n' => return Ok((String::from_utf8_lossy(&line).into_owned(),false)),
+            byte => {
+                escaped = !raw && !escaped && byte == b'\\';
+                line.push(byte);
+            }
+        }
+    }
+}
+
+/// Splits `line` into `count` values at the characters of `separators`, the way `read`
+/// assigns them. Runs of IFS whitespace are dropped around the values, and the last one
+/// keeps the rest of the line.
+fn split_line(line:&str,raw:bool,separators:&str,count:usize)->Vec<String>{
+    // characters along with whether a backslash made them literal
+    let mut chars = vec![];
+    let mut iter = line.chars();
+    while let Some(char) = iter.next() {
+        match char {
+            '\\' if !raw => chars.extend(iter.next().map(|next| (next,true))),
+            char => chars.push((char,false))
+        }
+    }
+    let is_separator = |(char,literal):(char,bool)| !literal && separators.contains(char);
+    let is_space = |(char,literal):(char,bool)| is_separator((char,literal)) && matches!(char,' ' | '\t' | '\n');
+    let text = |chars:&[(char,bool)]| chars.iter().map(|(char,_)| char).collect::<String>();
+    let mut index = 0;
+    let skip_spaces = |index:&mut usize| while *index < chars.len() && is_space(chars[*index]) {
+        *index += 1;
+    };
+    skip_spaces(&mut index);
+    let mut values = vec![];
+    while values.len() + 1 < count && index < chars.len(){
+        let start = index;
+        while index < chars.len() && !is_separator(chars[index]) {
+            index += 1;
+        }
+        values.push(text(&chars[start..index]));
+        skip_spaces(&mut index);
+        if index < chars.len() && is_separator(chars[index]) && !is_space(chars[index]){
+            index += 1;
+            skip_spaces(&mut index);
+        }
+    }
+    let mut end = chars.len();
+    while end > index && is_space(chars[end - 1]) {
+        end -= 1;
+    }
+    values.push(text(&chars[index..end]));
+    values.resize(count,String::from(""));
+    values
+}
+
+fn usage_error(message:&str)->i32{
+    eprintln!("hsh: {message}");
+    2
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn args(args:&[&str])->Vec<String>{
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_set_get_and_unset() {
+        let mut variables = Variables::new();
+        variables.set("greeting","hello").unwrap();
+        assert_eq!(variables.get("greeting"),Some("hello"));
+        variables.unset("greeting").unwrap();
+        assert_eq!(variables.get("greeting"),None);
+        assert_eq!(variables.set("1x","a"),Err(VariableError::InvalidName { name: String::from("1x") }));
+    }
+
+    #[test]
+    fn test_readonly_and_integer_attributes() {
+        let mut variables = Variables::new();
+        assert_eq!(readonly_builtin(&mut variables,&args(&["fixed=1"])),0);
+        assert_eq!(variables.set("fixed","2"),Err(VariableError::Readonly { name: String::from("fixed") }));
+        assert!(variables.unset("fixed").is_err());
+        assert_eq!(variables.get("fixed"),Some("1"));
+
+        assert_eq!(declare_builtin(&mut variables,&args(&["-i","count=2*3"])),0);
+        assert_eq!(variables.get("count"),Some("6"));
+        variables.set("count","count+1").unwrap();
+        assert_eq!(variables.get("count"),Some("7"));
+    }
+
+    #[test]
+    fn test_exported_variables_form_the_environment() {
+        let mut variables = Variables::new();
+        variables.set("LOCAL","no").unwrap();
+        assert_eq!(export_builtin(&mut variables,&args(&["SHARED=yes","LATER"])),0);
+        assert_eq!(variables.environment(),vec![CString::new("SHARED=yes").unwrap()]);
+        variables.set("LATER","now").unwrap();
+        let mut environment = variables.environment();
+        environment.sort();
+        assert_eq!(environment,vec![CString::new("LATER=now").unwrap(),CString::new("SHARED=yes").unwrap()]);
+    }
+
+    #[test]
+    fn test_read_splits_lines_at_ifs() {
+        assert_eq!(split_line("  a  b  c  ",false," \t\n",2),vec!["a","b  c"]);
+        assert_eq!(split_line("a:b::c",false,":",4),vec!["a","b","","c"]);
+        assert_eq!(split_line("x",false," ",3),vec!["x","",""]);
+        assert_eq!(split_line("a\\ b c",false," ",2),vec!["a b","c"]);
+        assert_eq!(split_line("a\\ b c",true," ",2),vec!["a\\","b c"]);
+    }
+}
@@ -1,35 +1,71 @@
-use std::io::Write;
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::os::fd::AsFd;
 mod error;
-use core::{TokenizedOutput,match_expression, load_startup_path};
+use core::load_startup_path;
+use core::executor::Shell;
+use core::parser::parse_program;
+use core::error::render_diagnostic;
 
 
 fn main(){
     
-    
+    let mut reader = stdin_reader();
+    let mut shell = Shell::new();
+    // prompts and job control are only for a terminal, not a script piped in
+    let interactive = std::io::stdin().is_terminal();
+    shell.interactive = interactive;
     loop {
-        let mut input_line = String::from("");
-        print!("{} % ",load_startup_path());
-        std::io::stdout().flush().expect("Failed to flush stdout"); // Flush stdout to ensure prompt is displayed
+        shell.reap_background_jobs();
+        if interactive{
+            print!("{} % ",load_startup_path());
+            std::io::stdout().flush().expect("Failed to flush stdout"); // Flush stdout to ensure prompt is displayed
+        }
 
-        std::io::stdin().read_line(&mut input_line) // Read a line from stdin into the `input` string
-            .expect("Failed to read line");
-        let trimmed_input = input_line.trim().to_string();
-        let tokens = tokenize_input(&trimmed_input);
-        match_expression(tokens)
+        let Some(input_line) = read_input(&mut reader, interactive) else {
+            // end of input
+            break;
+        };
+        match parse_program(&input_line) {
+            Ok(program) => {
+                shell.execute_program("<stdin>",&input_line,&program);
+            },
+            Err(err) => {
+                eprintln!("{}",render_diagnostic("<stdin>", &input_line, err.span(), &err.to_string()));
+                shell.last_status = 2;
+            }
+        }
     }
+    // like `sh < script`, exit with the status of the last command
+    std::io::stdout().flush().expect("Failed to flush stdout");
+    std::process::exit(shell.last_status);
 }
 
-fn tokenize_input<'a>(input_line:&'a String)->TokenizedOutput<'a>{
-    let string_split:Vec<&str> = input_line.split_whitespace().collect();
-    if string_split.len() == 0{
-        //return error of empty input
-    }
-    //As no argument case handled above, shouldn't get an error here
-    let command = string_split.first().map(|s|*s).unwrap_or("No command passed!");
-    let args = string_split[1..].to_vec();
-    TokenizedOutput{
-        command,
-        args
-    }
+/// Standard input read a byte at a time, so nothing after the current command is taken
+/// away from `read` or the programs the shell runs, which read the same fd.
+fn stdin_reader()->BufReader<File>{
+    let stdin = std::io::stdin().as_fd().try_clone_to_owned().expect("Failed to duplicate stdin");
+    BufReader::with_capacity(1,File::from(stdin))
 }
 
+/// Reads a line and keeps appending lines while the command is unfinished, e.g. after a
+/// trailing backslash or an `if` without its `fi`. Returns None once the reader is exhausted.
+fn read_input(reader:&mut impl BufRead,interactive:bool)->Option<String>{
+    let mut input = String::from("");
+    loop {
+        let mut line = String::from("");
+        let bytes_read = reader.read_line(&mut line) // Read a line from the reader into `line`
+            .expect("Failed to read line");
+        if bytes_read == 0{
+            return if input.is_empty() { None } else { Some(input) };
+        }
+        input.push_str(&line);
+        if !parse_program(&input).is_err_and(|err| err.is_incomplete()){
+            return Some(input);
+        }
+        if interactive{
+            print!("> ");
+            std::io::stdout().flush().expect("Failed to flush stdout");
+        }
+    }
+}